@@ -1,5 +1,5 @@
-use std::{fs, path::{Path, PathBuf}, net::TcpStream, io::{BufReader, BufRead, Write, BufWriter, Read}, thread, fs::File, collections::HashMap, time::Instant};
-use action_parser::{placement_stats::CumulativePlacementStats, replay_response::PlacementStats, player_stats::PlayerStats};
+use std::{fs, path::{Path, PathBuf}, io::{BufReader, Read}, fs::File, collections::HashMap, time::Instant};
+use action_parser::{placement_stats::CumulativePlacementStats, player_stats::PlayerStats, replay_source::{munch_players, sanitize_string, NativeDecoder, ParserClient, ReplaySource}};
 use notify::{Config as WatcherConfig, RecommendedWatcher, RecursiveMode, Watcher};
 
 
@@ -64,7 +64,7 @@ fn parse_file(path: PathBuf, file_data: &mut HashMap<PathBuf, Vec<(String, Cumul
     let mut replay = String::new();
     reader.read_to_string(&mut replay).expect("unable to read files");
     let instant = Instant::now();
-    match process_replay(&replay){
+    match replay_source().placements(&sanitize_string(&replay), &[]).and_then(munch_players){
         Ok(players) => {
             println!("successfully parsed file at {:?} in {}ms", path, instant.elapsed().as_millis());
             file_data.insert(path, players);
@@ -75,6 +75,12 @@ fn parse_file(path: PathBuf, file_data: &mut HashMap<PathBuf, Vec<(String, Cumul
     }
 }
 
+///native decoding unless USE_EXTERNAL_PARSER=true, same as the server
+fn replay_source() -> Box<dyn ReplaySource>{
+    let external_parser: bool = std::env::var("USE_EXTERNAL_PARSER").ok().and_then(|s: String| s.parse().ok()).unwrap_or(false);
+    if external_parser { Box::new(ParserClient::from_env()) } else { Box::new(NativeDecoder) }
+}
+
 fn output_data(path: &Path, file_data: &HashMap<PathBuf, Vec<(String, CumulativePlacementStats)>>){
     let mut player_stats: HashMap<String, CumulativePlacementStats> = HashMap::new();
     for players in file_data.values(){
@@ -101,103 +107,3 @@ fn output_data(path: &Path, file_data: &HashMap<PathBuf, Vec<(String, Cumulative
 
     println!("WROTE DATA");
 }
-
-
-#[derive(Debug)]
-enum ReplayError{
-    Unsupported,
-    Unparsable,
-    Unmunchable,
-    Corrupt,
-    Connection
-}
-
-impl std::error::Error for ReplayError {}
-
-impl std::fmt::Display for ReplayError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self{
-            ReplayError::Unsupported => write!(f, "The replay's version is unsupported."),
-            ReplayError::Unparsable => write!(f, "The replay was unable to be identified as a valid replay."),
-            ReplayError::Unmunchable => write!(f, "The replay's data was unable to be processed into stats."),
-            ReplayError::Corrupt => write!(f, "The replay is corrupt, no data was able to be processed."),
-            ReplayError::Connection => write!(f, "A connection error occurred with the replay parser."),
-        }
-    }
-}
-
-
-fn sanitize_string(s: &str)->String{
-    s.trim_start_matches('\u{feff}').trim_end_matches('\n').trim_end_matches('\r').to_string()
-}
-
-
-fn process_replay(replay: &str)->Result<Vec<(String, CumulativePlacementStats)>, ReplayError>{
-    let addr: usize = std::env::var("TETRIO_PARSER_PORT").ok().and_then(|s: String| s.parse().ok()).unwrap_or(8080);
-    let addr = format!("127.0.0.1:{}",addr);
-
-    let stream = TcpStream::connect(addr).or(Err(ReplayError::Connection))?;
-    let mut reader = BufReader::new(stream.try_clone().or(Err(ReplayError::Connection))?);
-    let mut writer = BufWriter::new(stream);
-    writer.write_all(sanitize_string(replay).as_bytes()).or(Err(ReplayError::Connection))?;
-    writer.write_all("\n".as_bytes()).or(Err(ReplayError::Connection))?;
-    writer.flush().or(Err(ReplayError::Connection))?;
-
-    let mut supported = String::new();
-    reader.read_line(&mut supported).or(Err(ReplayError::Unparsable))?;
-    let supported: bool = sanitize_string(&supported).parse().or(Err(ReplayError::Unparsable))?;
-
-    if !supported{
-        return Err(ReplayError::Unsupported)
-    }
-
-    let mut names = String::new();
-    reader.read_line(&mut names).or(Err(ReplayError::Unparsable))?;
-    let names: Vec<_> = sanitize_string(&names)
-        .split(' ')
-        .map(|s| s.to_string())
-        .collect();
-
-    let mut num_games = String::new();
-    reader.read_line(&mut num_games).or(Err(ReplayError::Unparsable))?;
-    let num_games: usize = sanitize_string(&num_games).parse().or(Err(ReplayError::Unparsable))?;
-
-    writer.write_all(names.len().to_string().as_bytes()).or(Err(ReplayError::Connection))?;
-    writer.write_all("\n".as_bytes()).or(Err(ReplayError::Connection))?;
-    writer.flush().or(Err(ReplayError::Connection))?;
-
-    let mut fully_corrupt = true;
-
-    let mut stats = Vec::new();
-
-    for name in names {
-        let mut cumulative_stats = CumulativePlacementStats::default();
-        let mut handles = Vec::new();
-
-        writer.write_all(name.as_bytes()).or(Err(ReplayError::Connection))?; //request stats for [name] from parser
-        writer.write_all("\n".as_bytes()).or(Err(ReplayError::Connection))?;
-        writer.flush().or(Err(ReplayError::Connection))?;
-
-        for _ in 0..num_games {
-            let mut game = String::new();
-            reader.read_line(&mut game).or(Err(ReplayError::Unparsable))?; //parse individual placement sequences for each game
-            if sanitize_string(&game)=="CORRUPT"{
-                continue;
-            }
-            fully_corrupt = false;
-            let placements: Vec<PlacementStats> = serde_json::from_str(&game).or(Err(ReplayError::Unmunchable))?;
-
-            handles.push(thread::spawn(move || CumulativePlacementStats::from(placements.as_slice())));
-        }
-         
-        while let Some(handle) = handles.pop(){
-            let game_stats = handle.join().or(Err(ReplayError::Unmunchable))?;
-            cumulative_stats.absorb(game_stats);
-        }
-        stats.push((name, cumulative_stats))
-    }
-    if fully_corrupt{
-        return Err(ReplayError::Corrupt);
-    }
-    Ok(stats)
-}
\ No newline at end of file
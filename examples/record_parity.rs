@@ -0,0 +1,25 @@
+use std::{fs, path::Path};
+use action_parser::replay_source::{sanitize_string, ParserClient, PlacementDirectory, ReplaySource};
+
+//records what the csdotnet parser makes of every replay in a directory next to it, the fixtures tests/parity.rs checks native decoding against
+fn main() {
+    let path = std::env::args()
+        .nth(1).unwrap_or("tests/fixtures/parity".to_string());
+    let path = Path::new(&path);
+    let parser = ParserClient::from_env(); //TETRIO_PARSER_PORT, like the server
+    let fixtures = PlacementDirectory::new(path);
+
+    for entry in fs::read_dir(path).expect("able to read fixture path"){
+        let path = entry.expect("file entry error").path();
+        if !path.extension().and_then(|ext|ext.to_str()).is_some_and(|ext|ext == "ttrm" || ext == "ttr"){
+            continue;
+        }
+        let handle = path.file_stem().and_then(|stem|stem.to_str()).expect("replay file name is utf8");
+        let replay = fs::read_to_string(&path).expect("unable to read replay");
+        match parser.placements(&sanitize_string(&replay), &[]).map(|players|fixtures.store(handle, &players)){
+            Ok(Ok(())) => println!("recorded {:?}", path),
+            Ok(Err(e)) => println!("error writing fixture for {:?}: {}", path, e),
+            Err(e) => println!("error parsing file at {:?}: {}", path, e),
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use crate::replay_response::ClearType;
+use crate::ruleset::{B2bRules, Ruleset};
+
 const ATTACK_TABLE: [[[usize; 21]; 9]; 5] = [
     // straight taken from osk's table: https://cdn.discordapp.com/attachments/674421736162197515/716081165886423110/2020-05-30_02-07-18.png
     [
@@ -122,8 +125,15 @@ const ATTACK_TABLE: [[[usize; 21]; 9]; 5] = [
         ], // T-Spin Triple
     ], // B2B 4
 ];
+///index of allspin triples, which have no row in the attack table
+const ALLSPIN_TRIPLE: usize = 9;
+///attack of every index before combo and b2b, for rulesets without an attack table
+const BASE_ATTACK: [usize; 10] = [0, 1, 2, 4, 0, 2, 1, 4, 6, 2];
+///everything from quads on keeps b2b, the plain single, double and triple before it break the chain
+const FIRST_B2B_INDEX: usize = 3;
+
 ///Get attack amount from provided tetrio attack table, accurate enough for most cases.
-pub fn get_indexed_attack(clear_type_index: usize, combo: usize, btb: usize) -> usize {
+fn table_attack(clear_type_index: usize, combo: usize, btb: usize) -> usize {
     let btb_level = btb_level(btb);
     if btb_level < ATTACK_TABLE.len() {
         if combo < 21 {
@@ -134,7 +144,77 @@ pub fn get_indexed_attack(clear_type_index: usize, combo: usize, btb: usize) ->
         }
         return ATTACK_TABLE[btb_level][clear_type_index][20];
     }
-    get_indexed_attack(clear_type_index, combo, 0) + btb_level
+    table_attack(clear_type_index, combo, 0) + btb_level
+}
+
+///Get attack amount of an attack table index under a ruleset, before the garbage multiplier.
+///btb is ignored for clears that break the chain instead of adding to it
+pub fn get_indexed_attack(
+    ruleset: &Ruleset,
+    clear_type_index: usize,
+    combo: usize,
+    btb: usize,
+) -> usize {
+    let btb = if clear_type_index < FIRST_B2B_INDEX {
+        0
+    } else {
+        btb
+    };
+    if ruleset.uses_attack_table() {
+        if clear_type_index == ALLSPIN_TRIPLE {
+            return table_attack(2, combo, 0) + table_attack(3, combo, btb)
+                - table_attack(3, combo, 0);
+        } // no row of its own, a triple plus the b2b bonus a quad would get
+        return table_attack(clear_type_index, combo, btb);
+    }
+    let mut attack = BASE_ATTACK[clear_type_index] as f64;
+    if btb > 0 {
+        attack += match ruleset.b2b {
+            B2bRules::Chaining => btb_level(btb) as f64,
+            B2bRules::Flat | B2bRules::Surge => 1.0,
+        };
+    }
+    ruleset.combo_table.apply(attack, combo).floor() as usize
+}
+
+///Get attack amount of a clear under a ruleset, combo and btb being the chains before it.
+pub fn get_clear_attack(
+    ruleset: &Ruleset,
+    clear_type: ClearType,
+    combo: usize,
+    btb: usize,
+    perfect_clear: bool,
+) -> usize {
+    let index = match clear_type {
+        ClearType::Single => 0,
+        ClearType::Double => 1,
+        ClearType::Triple => 2,
+        ClearType::Quad | ClearType::Penta | ClearType::AllspinQuad => 3,
+        ClearType::TspinMiniSingle | ClearType::AllspinSingle => 4,
+        ClearType::TspinSingle => 5,
+        ClearType::TspinMiniDouble | ClearType::AllspinDouble => 6,
+        ClearType::TspinDouble => 7,
+        ClearType::TspinTriple | ClearType::TspinQuad | ClearType::TspinPenta => 8,
+        ClearType::AllspinTriple => ALLSPIN_TRIPLE,
+        ClearType::None
+        | ClearType::TspinMini
+        | ClearType::Tspin
+        | ClearType::Allspin
+        | ClearType::PerfectClear => return 0,
+    };
+    let mut attack = get_indexed_attack(ruleset, index, combo, btb);
+    if perfect_clear {
+        attack += ruleset.pc_bonus;
+    }
+    (attack as f64 * ruleset.garbage_multiplier).floor() as usize
+}
+
+///Get the surge a broken b2b chain releases, b2b counting the clears after its first.
+pub fn get_surge_attack(ruleset: &Ruleset, b2b: usize) -> usize {
+    if ruleset.b2b != B2bRules::Surge || b2b < 4 {
+        return 0;
+    }
+    (b2b as f64 * ruleset.garbage_multiplier).floor() as usize
 }
 
 fn btb_level(btb: usize) -> usize {
@@ -162,5 +242,56 @@ fn btb_level(btb: usize) -> usize {
     if btb < 1370 {
         return 7;
     }
-    8// next "level" starts at ~3725 but we're keeping it oskreveal
+    8 // next "level" starts at ~3725 but we're keeping it oskreveal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay_response::SpinRules;
+
+    #[test]
+    fn all_mini_clear_types_attack() {
+        let s1 = Ruleset {
+            spin_rules: SpinRules::AllMini,
+            ..Ruleset::tetrio_s1()
+        };
+        let s2 = Ruleset::tetrio_s2();
+        //(clear type, s1 without and with b2b, s2 without and with b2b)
+        let cases = [
+            (ClearType::Allspin, [0, 0], [0, 0]),
+            (ClearType::AllspinSingle, [0, 1], [0, 1]),
+            (ClearType::AllspinDouble, [1, 2], [1, 2]),
+            (ClearType::AllspinTriple, [2, 3], [2, 3]),
+            (ClearType::AllspinQuad, [4, 5], [4, 5]),
+        ];
+        for (clear_type, table, flat) in cases {
+            for (btb, (table, flat)) in table.into_iter().zip(flat).enumerate() {
+                assert_eq!(
+                    get_clear_attack(&s1, clear_type, 0, btb, false),
+                    table,
+                    "{clear_type:?} s1 b2b {btb}"
+                );
+                assert_eq!(
+                    get_clear_attack(&s2, clear_type, 0, btb, false),
+                    flat,
+                    "{clear_type:?} s2 b2b {btb}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn surge_needs_a_long_chain_and_surge_rules() {
+        let s2 = Ruleset::tetrio_s2();
+        assert_eq!(get_surge_attack(&s2, 3), 0);
+        assert_eq!(get_surge_attack(&s2, 4), 4);
+        assert_eq!(get_surge_attack(&s2, 9), 9);
+        assert_eq!(get_surge_attack(&Ruleset::tetrio_s1(), 9), 0);
+        let multiplied = Ruleset {
+            garbage_multiplier: 1.5,
+            ..s2
+        };
+        assert_eq!(get_surge_attack(&multiplied, 5), 7);
+    }
 }
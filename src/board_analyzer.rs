@@ -1,4 +1,5 @@
 use crate::replay_response::{Board, MinoType};
+use bitris::prelude::*;
 
 pub fn get_height(board: &Board) -> usize {
     for y in 0..40 {
@@ -70,3 +71,166 @@ pub fn has_cheese(board: &Board) -> bool {
     }
     false
 }
+
+///bitris board with every non empty cell filled, y up
+pub fn to_board64(board: &Board) -> Board64 {
+    let mut board64 = Board64::blank();
+    for y in 0..40 {
+        for x in 0..10 {
+            if board[(39 - y) * 10 + x] != MinoType::Empty {
+                board64.set_at(Location {
+                    x: x as i32,
+                    y: y as i32,
+                });
+            }
+        }
+    }
+    board64
+}
+
+pub fn to_shape(mino: MinoType) -> Option<Shape> {
+    match mino {
+        MinoType::Z => Some(Shape::Z),
+        MinoType::L => Some(Shape::L),
+        MinoType::O => Some(Shape::O),
+        MinoType::S => Some(Shape::S),
+        MinoType::I => Some(Shape::I),
+        MinoType::J => Some(Shape::J),
+        MinoType::T => Some(Shape::T),
+        _ => None,
+    }
+}
+
+pub fn from_shape(shape: Shape) -> MinoType {
+    match shape {
+        Shape::Z => MinoType::Z,
+        Shape::L => MinoType::L,
+        Shape::O => MinoType::O,
+        Shape::S => MinoType::S,
+        Shape::I => MinoType::I,
+        Shape::J => MinoType::J,
+        Shape::T => MinoType::T,
+    }
+}
+
+#[derive(Debug)]
+pub enum ReconstructError {
+    NoPlacement,
+    Ambiguous(Vec<BlPlacement>), //every placement that explains the diff
+}
+
+impl std::error::Error for ReconstructError {}
+
+impl std::fmt::Display for ReconstructError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReconstructError::NoPlacement => {
+                f.write_str("no legal placement turns the previous board into the next")
+            }
+            ReconstructError::Ambiguous(candidates) => write!(
+                f,
+                "{} different placements turn the previous board into the next",
+                candidates.len()
+            ),
+        }
+    }
+}
+
+///finds the placement of `shape` that turned `previous` into `next`. lines are cleared the way the
+///placement would have, and `garbage_added` rows pushed in at the bottom afterwards are skipped.
+///only placements reachable from spawn with srs are considered
+pub fn reconstruct_placement(
+    previous: &Board,
+    next: &Board,
+    shape: MinoType,
+    garbage_added: usize,
+) -> Result<BlPlacement, ReconstructError> {
+    let shape = to_shape(shape).ok_or(ReconstructError::NoPlacement)?;
+    if garbage_added >= 40 {
+        return Err(ReconstructError::NoPlacement); //the garbage pushed every row the piece could be in off the board
+    }
+    let board = to_board64(previous);
+    let spawn = Piece::new(shape, Orientation::North)
+        .with(cc(4, 21))
+        .to_bl_placement();
+    if !spawn.is_in_free_space(&board) {
+        return Err(ReconstructError::NoPlacement);
+    }
+
+    let explains_diff = |placement: &BlPlacement| {
+        let mut placed = board;
+        placement.place_on_and_clear_lines(&mut placed);
+        (0..40 - garbage_added).all(|y| {
+            (0..10).all(|x| {
+                let filled = next[(39 - y - garbage_added) * 10 + x] != MinoType::Empty;
+                placed.is_occupied_at(Location {
+                    x: x as i32,
+                    y: y as i32,
+                }) == filled
+            })
+        })
+    };
+
+    let mut candidates: Vec<_> = MoveRules::default()
+        .generate_minimized_moves(board, spawn)
+        .into_iter()
+        .filter(explains_diff)
+        .collect();
+    //minimized moves already merge placements that cover the same cells
+
+    match candidates.len() {
+        0 => Err(ReconstructError::NoPlacement),
+        1 => Ok(candidates.remove(0)),
+        _ => Err(ReconstructError::Ambiguous(candidates)),
+    }
+}
+
+///a board from its bottom rows, top row first, a piece's letter for its minos, `#` or `G` for
+///garbage and anything else empty
+#[cfg(test)]
+pub fn board_from_rows(rows: &[&str]) -> Board {
+    let mut board = vec![MinoType::Empty; 400];
+    for (i, row) in rows.iter().enumerate() {
+        let y = 40 - rows.len() + i;
+        for (x, cell) in row.chars().enumerate() {
+            board[y * 10 + x] = match cell {
+                'Z' => MinoType::Z,
+                'L' => MinoType::L,
+                'O' => MinoType::O,
+                'S' => MinoType::S,
+                'I' => MinoType::I,
+                'J' => MinoType::J,
+                'T' => MinoType::T,
+                '#' | 'G' => MinoType::Garbage,
+                _ => MinoType::Empty,
+            };
+        }
+    }
+    board
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconstructs_a_placement_under_garbage() {
+        let previous = board_from_rows(&[]);
+        let next = board_from_rows(&["##........", "##........", "#########."]);
+        let placement = reconstruct_placement(&previous, &next, MinoType::O, 1).unwrap();
+        let mut cells = placement.locations().map(|l| (l.x, l.y));
+        cells.sort();
+        assert_eq!(cells, [(0, 0), (0, 1), (1, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn garbage_over_the_whole_board_has_no_placement() {
+        let board = board_from_rows(&[]);
+        for garbage_added in [40, 41, 100] {
+            assert!(matches!(
+                reconstruct_placement(&board, &board, MinoType::T, garbage_added),
+                Err(ReconstructError::NoPlacement)
+            ));
+        }
+    }
+}
@@ -1,60 +1,592 @@
-use std::{fs::{create_dir, read_dir, File}, io::{BufReader, BufWriter, Write}, path::Path, time::SystemTime, collections::HashMap};
-use std::sync::atomic::{AtomicBool, Ordering};
-use crate::placement_stats::CumulativePlacementStats;
-
-const TIME_TO_LIVE : u64 = 60*60*5; //max secs elapsed to keep file
-const CACHE_PATH : &str = ".replayCache";
-const MAX_CACHED_FILES: usize = 1000;
-const MAX_TRIMMED_FILES: usize = 800;
-
-static TRIMMING_CACHE : AtomicBool = AtomicBool::new(false);
-
-pub fn get_cached_stats(handle: &str) -> Option<HashMap<String, CumulativePlacementStats>>{
-    let file_path = Path::new(CACHE_PATH).join(Path::new(handle));
-    if !file_path.exists(){
-        return None
-    }
-    let file = File::open(file_path).expect("unable to open cached replay file");
-    let reader = BufReader::new(file);
-    serde_json::from_reader(reader).ok()
-}
-
-pub fn set_cached_stats(handle: &str, players: &HashMap<String, CumulativePlacementStats>){ //supposed to be an endpoint, should we force a consumption?
-    let file_path = Path::new(CACHE_PATH).join(Path::new(handle));
-    let file = File::create(file_path).expect("unable to open cached replay file");
-    let mut writer = BufWriter::new(file);
-    writer.write_all(serde_json::to_string(players).expect("unable to serialize stats").as_bytes()).unwrap();
-    writer.flush().unwrap();
-
-    let mut files : Vec<_> = read_dir(Path::new(CACHE_PATH)).expect("unable to read replay cache dir").filter_map(|x|x.ok()).filter(|x|x.metadata().unwrap().is_file()).collect();
-    if files.len() > MAX_CACHED_FILES && !TRIMMING_CACHE.load(Ordering::SeqCst){
-        TRIMMING_CACHE.store(true, Ordering::SeqCst);
-        std::thread::spawn(move ||{
-            files.sort_by(|x,y|x.metadata().unwrap().modified().unwrap().cmp(&y.metadata().unwrap().modified().unwrap()));
-            for i in 0..MAX_TRIMMED_FILES{
-                std::fs::remove_file(files[i].path()).expect("unable to remove overflowed file");
+use std::{fs::{create_dir_all, read_dir, remove_file, rename, File}, io::{BufReader, BufWriter, ErrorKind, Write}, path::{Path, PathBuf}, time::{Duration, SystemTime}, collections::HashMap, num::NonZeroUsize};
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex, MutexGuard};
+use lru::LruCache;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use crate::placement_stats::{CumulativePlacementStats, STATS_VERSION};
+use crate::files::{is_file_handle, temp_path};
+use crate::ruleset::Ruleset;
+
+pub const CACHE_PATH : &str = ".replayCache";
+pub const SQLITE_PATH : &str = ".replayCache.sqlite3";
+const JANITOR_INTERVAL : Duration = Duration::from_secs(60);
+
+///stats of every cached player of a replay, by name
+pub type CachedStats = HashMap<String, CumulativePlacementStats>;
+
+///what an entry was computed with, entries stamped differently from the running server are stale
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStamp{
+    pub version: u32,
+    pub ruleset: String,
+}
+
+impl CacheStamp{
+    pub fn current() -> Self{
+        Self{ version: STATS_VERSION, ruleset: Ruleset::presets_id() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry{
+    #[serde(flatten)]
+    pub stamp: CacheStamp,
+    pub players: CachedStats,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheUsage{
+    pub entries: usize, //replays, not players
+    pub bytes: usize,
+}
+
+///how much the janitor lets a cache keep
+#[derive(Debug, Clone, Copy)]
+pub struct CacheLimits{
+    pub ttl: Duration, //since the entry was written, reads don't extend it
+    pub max_entries: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for CacheLimits{
+    fn default() -> Self{
+        Self{ ttl: Duration::from_secs(60*60*5), max_entries: 1000, max_bytes: 512*1024*1024 }
+    }
+}
+
+impl CacheLimits{
+    ///CACHE_TTL_SECS, CACHE_MAX_ENTRIES and CACHE_MAX_BYTES, the defaults for any unset
+    pub fn from_env() -> Self{
+        let default = Self::default();
+        let var = |name: &str|std::env::var(name).ok().and_then(|s: String|s.parse::<u64>().ok());
+        Self{
+            ttl: var("CACHE_TTL_SECS").map_or(default.ttl, Duration::from_secs),
+            max_entries: var("CACHE_MAX_ENTRIES").map_or(default.max_entries, |x|x as usize),
+            max_bytes: var("CACHE_MAX_BYTES").map_or(default.max_bytes, |x|x as usize),
+        }
+    }
+}
+
+///somewhere merged stats are kept between requests, keyed by replay id or upload hash
+pub trait StatsCache: Send + Sync{
+    fn get(&self, handle: &str) -> Option<CacheEntry>;
+    ///replaces the replay's entry, readers see either the old one or the new one and never half of it
+    fn set(&self, handle: &str, entry: &CacheEntry);
+    ///tracked as entries come and go, never by walking the store
+    fn usage(&self) -> CacheUsage;
+    ///entries stamped with anything but `stamp`, including ones too old to have a stamp. this one may walk the store
+    fn count_stale(&self, stamp: &CacheStamp) -> usize;
+    ///drops expired entries, then the least recently read until within limits. returns how many went
+    fn evict(&self, limits: &CacheLimits) -> usize;
+}
+
+///the only thing that evicts, so writers never race each other over what to delete
+pub fn spawn_janitor(cache: Arc<dyn StatsCache>, limits: CacheLimits) -> tokio::task::JoinHandle<()>{
+    tokio::spawn(async move{
+        let mut interval = tokio::time::interval(JANITOR_INTERVAL);
+        loop{
+            interval.tick().await;
+            let cache = Arc::clone(&cache);
+            match tokio::task::spawn_blocking(move||cache.evict(&limits)).await{
+                Ok(0) => {},
+                Ok(evicted) => println!("cache janitor evicted {} replays", evicted),
+                Err(e) => eprintln!("cache janitor failed! {}", e),
             }
-            TRIMMING_CACHE.store(false, Ordering::SeqCst);
+        }
+    })
+}
+
+///handles to evict so the rest fits within limits, given (handle, bytes) of every live entry most recently read first
+fn over_limits<'a>(limits: &CacheLimits, entries: impl IntoIterator<Item=(&'a str, usize)>) -> Vec<String>{
+    let (mut count, mut bytes) = (0, 0);
+    entries.into_iter().filter(|&(_, len)|{
+        count += 1;
+        bytes += len;
+        count > limits.max_entries || bytes > limits.max_bytes
+    }).map(|(handle, _)|handle.to_string()).collect() //once one doesn't fit, nothing older does either
+}
+
+///recovers a poisoned lock, the guarded state is updated in single steps so a panic can't leave it half written
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T>{
+    mutex.lock().unwrap_or_else(|e|e.into_inner())
+}
+
+fn expired(created: SystemTime, now: SystemTime, limits: &CacheLimits) -> bool{
+    now.duration_since(created).is_ok_and(|age|age > limits.ttl)
+}
+
+struct FileMeta{
+    bytes: usize,
+    created: SystemTime,
+    accessed: SystemTime,
+}
+
+///one json file per handle in a directory, the original cache
+pub struct FsCache{
+    dir: PathBuf,
+    index: Mutex<HashMap<String, FileMeta>>, //every file in dir, last access is only tracked here
+}
+
+impl FsCache{
+    ///creates the directory if needed and indexes its files, the only full scan it does.
+    ///anything unreadable is logged and left out, the janitor expires the rest
+    pub fn open(dir: impl AsRef<Path>) -> Self{
+        let dir = dir.as_ref().to_path_buf();
+        let mut index = HashMap::new();
+        if let Err(e) = create_dir_all(&dir){
+            eprintln!("unable to create replay cache {}! {}", dir.display(), e);
+        }
+        let entries = read_dir(&dir).inspect_err(|e|eprintln!("unable to read replay cache dir! {}", e));
+        for entry in entries.into_iter().flatten(){
+            let (path, metadata) = match entry.and_then(|entry|Ok((entry.path(), entry.metadata()?))){
+                Ok(file) => file,
+                Err(e) => {eprintln!("unable to read cached replay metadata! {}", e); continue;}
+            };
+            let Some(handle) = path.file_name().and_then(|name|name.to_str()).map(str::to_string) else {continue;};
+            if !metadata.is_file(){continue;}
+            if handle.starts_with('.'){
+                remove_logged(&path); //a temp file from a write that never finished
+                continue;
+            }
+            let created = metadata.created().or(metadata.modified()).unwrap_or(SystemTime::now());
+            let accessed = metadata.accessed().unwrap_or(created);
+            index.insert(handle, FileMeta{ bytes: metadata.len() as usize, created, accessed });
+        }
+        Self{ dir, index: Mutex::new(index) }
+    }
+}
+
+///false if the file is still there
+fn remove_logged(path: &Path) -> bool{
+    match remove_file(path){
+        Ok(()) => true,
+        Err(e) if e.kind() == ErrorKind::NotFound => true,
+        Err(e) => {eprintln!("unable to remove {}! {}", path.display(), e); false},
+    }
+}
+
+impl StatsCache for FsCache{
+    fn get(&self, handle: &str) -> Option<CacheEntry>{
+        let mut index = lock(&self.index);
+        index.get_mut(handle)?.accessed = SystemTime::now();
+        let file = match File::open(self.dir.join(Path::new(handle))){
+            Ok(file) => file,
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound{
+                    eprintln!("unable to open cached replay {}! {}", handle, e);
+                }
+                index.remove(handle); //deleted underneath, treat it as never cached
+                return None;
+            }
+        };
+        drop(index);
+        serde_json::from_reader(BufReader::new(file)).ok()
+    }
+
+    fn set(&self, handle: &str, entry: &CacheEntry){ //supposed to be an endpoint, should we force a consumption?
+        if !is_file_handle(handle){
+            eprintln!("unable to cache replay {:?}, its handle can't name a file!", handle);
+            return;
+        } //get only reads indexed handles, so this is the one place a handle reaches the filesystem
+        let file_path = self.dir.join(Path::new(handle));
+        let temp_path = temp_path(&self.dir, handle);
+        let json = match serde_json::to_string(entry){
+            Ok(json) => json,
+            Err(e) => {eprintln!("unable to serialize stats of {}! {}", handle, e); return;}
+        };
+        let written = File::create(&temp_path).and_then(|file|{
+            let mut writer = BufWriter::new(file);
+            writer.write_all(json.as_bytes())?;
+            writer.flush()
         });
+        if let Err(e) = written{
+            eprintln!("unable to write cached replay {}! {}", handle, e);
+            remove_logged(&temp_path);
+            return;
+        }
+        //written aside and renamed over, so a reader never sees a partial file
+
+        let mut index = lock(&self.index); //held over the rename so the janitor can't delete the new file
+        if let Err(e) = rename(&temp_path, &file_path){
+            eprintln!("unable to move cached replay {}! {}", handle, e);
+            remove_logged(&temp_path);
+            return;
+        }
+        let now = SystemTime::now();
+        index.insert(handle.to_string(), FileMeta{ bytes: json.len(), created: now, accessed: now });
+    }
+
+    fn usage(&self) -> CacheUsage{
+        let index = lock(&self.index);
+        CacheUsage{ entries: index.len(), bytes: index.values().map(|meta|meta.bytes).sum() }
+    }
+
+    fn count_stale(&self, stamp: &CacheStamp) -> usize{
+        let handles: Vec<String> = lock(&self.index).keys().cloned().collect();
+        handles.iter().filter(|handle|{
+            let stamped = File::open(self.dir.join(Path::new(handle))).ok().and_then(|file|serde_json::from_reader::<_, CacheStamp>(BufReader::new(file)).ok());
+            stamped.as_ref() != Some(stamp)
+        }).count()
+    }
+
+    fn evict(&self, limits: &CacheLimits) -> usize{
+        let now = SystemTime::now();
+        let mut index = lock(&self.index);
+        let mut doomed: Vec<String> = index.iter().filter(|(_, meta)|expired(meta.created, now, limits)).map(|(handle, _)|handle.clone()).collect();
+        let mut live: Vec<_> = index.iter().filter(|(_, meta)|!expired(meta.created, now, limits)).collect();
+        live.sort_by_key(|(_, meta)|std::cmp::Reverse(meta.accessed));
+        doomed.extend(over_limits(limits, live.into_iter().map(|(handle, meta)|(handle.as_str(), meta.bytes))));
+
+        doomed.iter().filter(|handle|{
+            let removed = remove_logged(&self.dir.join(Path::new(handle)));
+            if removed{ index.remove(*handle); } //kept indexed if it's still there, so the next pass retries
+            removed
+        }).count()
+    }
+}
+
+///entries only live as long as the process, least recently used dropped first
+pub struct LruStatsCache{
+    entries: Mutex<LruCache<String, (CacheEntry, usize, SystemTime)>>, //entries with their serialized size and when they were set
+    bytes: AtomicUsize,
+}
+
+impl LruStatsCache{
+    pub fn new(capacity: usize) -> Self{
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self{ entries: Mutex::new(LruCache::new(capacity)), bytes: AtomicUsize::new(0) }
     }
 }
 
-pub fn initialize_cache(){
-    let cache_path = Path::new(CACHE_PATH);
-    if !cache_path.exists(){
-        create_dir(cache_path).expect("unable to create replay cache")
+impl StatsCache for LruStatsCache{
+    fn get(&self, handle: &str) -> Option<CacheEntry>{
+        lock(&self.entries).get(handle).map(|(entry, _, _)|entry.clone())
     }
 
-    let now = SystemTime::now();
+    fn set(&self, handle: &str, entry: &CacheEntry){
+        let len = serde_json::to_string(entry).map_or(0, |json|json.len());
+        let evicted = lock(&self.entries).push(handle.to_string(), (entry.clone(), len, SystemTime::now()));
+        if let Some((_, (_, evicted_len, _))) = evicted{
+            self.bytes.fetch_sub(evicted_len, Ordering::SeqCst);
+        }
+        self.bytes.fetch_add(len, Ordering::SeqCst);
+    }
 
-    for entry in read_dir(cache_path).expect("unable to read replay cache dir"){
-        if let Ok(entry) = entry{
-            let metadata = entry.metadata().expect("unable to read file metadata");
-            if !metadata.is_file(){continue;}
-            let created = metadata.created().expect("metadata contains created date");
-            if now.duration_since(created).unwrap().as_secs() > TIME_TO_LIVE{
-                std::fs::remove_file(entry.path()).expect("unable to remove expired file");
+    fn usage(&self) -> CacheUsage{
+        CacheUsage{ entries: lock(&self.entries).len(), bytes: self.bytes.load(Ordering::SeqCst) }
+    }
+
+    fn count_stale(&self, stamp: &CacheStamp) -> usize{
+        lock(&self.entries).iter().filter(|(_, (entry, _, _))|&entry.stamp != stamp).count()
+    }
+
+    fn evict(&self, limits: &CacheLimits) -> usize{
+        let now = SystemTime::now();
+        let mut entries = lock(&self.entries);
+        let mut doomed: Vec<String> = entries.iter().filter(|(_, (_, _, created))|expired(*created, now, limits)).map(|(handle, _)|handle.clone()).collect();
+        let live = entries.iter().filter(|(_, (_, _, created))|!expired(*created, now, limits)).map(|(handle, (_, len, _))|(handle.as_str(), *len));
+        doomed.extend(over_limits(limits, live)); //iterates most recently used first
+        for handle in &doomed{
+            if let Some((_, len, _)) = entries.pop(handle){
+                self.bytes.fetch_sub(len, Ordering::SeqCst);
+            }
+        }
+        doomed.len()
+    }
+}
+
+///one row per player per replay in an embedded database, indexed by handle and by player
+pub struct SqliteCache{
+    connection: Mutex<Connection>,
+}
+
+impl SqliteCache{
+    ///creates the tables if needed, expired rows are left to the janitor
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self>{
+        let connection = Connection::open(path)?;
+        connection.execute_batch("
+            CREATE TABLE IF NOT EXISTS stats (
+                handle TEXT NOT NULL,
+                player TEXT NOT NULL COLLATE NOCASE,
+                stats TEXT NOT NULL,
+                created INTEGER NOT NULL,
+                PRIMARY KEY (handle, player)
+            );
+            CREATE INDEX IF NOT EXISTS stats_player ON stats (player);
+            CREATE INDEX IF NOT EXISTS stats_created ON stats (created);
+        ")?;
+        for column in ["version INTEGER NOT NULL DEFAULT 0", "ruleset TEXT NOT NULL DEFAULT ''", "accessed INTEGER NOT NULL DEFAULT 0"]{
+            connection.execute(&format!("ALTER TABLE stats ADD COLUMN {}", column), []).ok(); //fails once the column exists
+        }
+        Ok(Self{ connection: Mutex::new(connection) })
+    }
+}
+
+fn now_secs() -> i64{
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |now|now.as_secs() as i64)
+}
+
+impl StatsCache for SqliteCache{
+    fn get(&self, handle: &str) -> Option<CacheEntry>{
+        let connection = lock(&self.connection);
+        let mut statement = connection.prepare_cached("SELECT player, stats, version, ruleset FROM stats WHERE handle = ?1").ok()?;
+        let rows = statement.query_map(params![handle], |row|Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, CacheStamp{ version: row.get(2)?, ruleset: row.get(3)? }))).ok()?;
+        let mut entry: Option<CacheEntry> = None;
+        for row in rows{
+            let (player, stats, stamp) = row.ok()?;
+            entry.get_or_insert_with(||CacheEntry{ stamp, players: HashMap::new() }).players.insert(player, serde_json::from_str(&stats).ok()?);
+        }
+        //rows of a handle are always written together, so they share a stamp
+        if entry.is_some(){
+            if let Err(e) = connection.execute("UPDATE stats SET accessed = ?2 WHERE handle = ?1", params![handle, now_secs()]){
+                eprintln!("unable to mark {} accessed! {}", handle, e);
+            }
+        }
+        entry
+    }
+
+    fn set(&self, handle: &str, entry: &CacheEntry){
+        let mut connection = lock(&self.connection);
+        let result = (||{
+            let transaction = connection.transaction()?;
+            transaction.execute("DELETE FROM stats WHERE handle = ?1", params![handle])?;
+            for (player, stats) in &entry.players{
+                let stats = serde_json::to_string(stats).map_err(|e|rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                transaction.execute("INSERT INTO stats (handle, player, stats, created, version, ruleset, accessed) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?4)", params![handle, player, stats, now_secs(), entry.stamp.version, entry.stamp.ruleset])?;
+            }
+            transaction.commit()
+        })();
+        if let Err(e) = result{
+            eprintln!("unable to cache replay {}: {}", handle, e);
+        }
+    }
+
+    fn usage(&self) -> CacheUsage{
+        let connection = lock(&self.connection);
+        connection.query_row("SELECT COUNT(DISTINCT handle), COALESCE(SUM(LENGTH(stats)), 0) FROM stats", [], |row|Ok(CacheUsage{ entries: row.get(0)?, bytes: row.get(1)? })).unwrap_or_default()
+    }
+
+    fn count_stale(&self, stamp: &CacheStamp) -> usize{
+        let connection = lock(&self.connection);
+        connection.query_row("SELECT COUNT(DISTINCT handle) FROM stats WHERE version != ?1 OR ruleset != ?2", params![stamp.version, stamp.ruleset], |row|row.get(0)).unwrap_or(0)
+    }
+
+    fn evict(&self, limits: &CacheLimits) -> usize{
+        let mut connection = lock(&self.connection);
+        let result = (||{
+            let transaction = connection.transaction()?;
+            let cutoff = now_secs() - limits.ttl.as_secs() as i64;
+            let expired: usize = transaction.query_row("SELECT COUNT(DISTINCT handle) FROM stats WHERE created < ?1", params![cutoff], |row|row.get(0))?;
+            transaction.execute("DELETE FROM stats WHERE created < ?1", params![cutoff])?;
+            let live = {
+                let mut statement = transaction.prepare("SELECT handle, SUM(LENGTH(stats)) FROM stats GROUP BY handle ORDER BY MAX(accessed) DESC")?;
+                let rows = statement.query_map([], |row|Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?)))?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()?
             };
+            let doomed = over_limits(limits, live.iter().map(|(handle, len)|(handle.as_str(), *len)));
+            for handle in &doomed{
+                transaction.execute("DELETE FROM stats WHERE handle = ?1", params![handle])?;
+            }
+            transaction.commit()?;
+            Ok::<_, rusqlite::Error>(expired + doomed.len())
+        })();
+        result.unwrap_or_else(|e|{eprintln!("unable to evict cached replays! {}", e); 0})
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    ///moves an entry's write and last read back in time, so tests don't have to sleep through ttls
+    trait Aged{
+        fn age(&self, handle: &str, by: Duration);
+        ///panics while holding the backend's lock, as a writer dying halfway would
+        fn poison(&self);
+    }
+
+    fn poison<T: Send>(mutex: &Mutex<T>){
+        std::thread::scope(|scope|{
+            let poisoner = scope.spawn(||{
+                let _guard = mutex.lock();
+                panic!("poisoning the cache lock");
+            });
+            assert!(poisoner.join().is_err());
+        });
+        assert!(mutex.is_poisoned());
+    }
+
+    impl Aged for FsCache{
+        fn age(&self, handle: &str, by: Duration){
+            let mut index = lock(&self.index);
+            let meta = index.get_mut(handle).unwrap();
+            meta.created -= by;
+            meta.accessed -= by;
+        }
+        fn poison(&self){
+            poison(&self.index);
+        }
+    }
+
+    impl Aged for SqliteCache{
+        fn age(&self, handle: &str, by: Duration){
+            lock(&self.connection).execute("UPDATE stats SET created = created - ?2, accessed = accessed - ?2 WHERE handle = ?1", params![handle, by.as_secs() as i64]).unwrap();
+        }
+        fn poison(&self){
+            poison(&self.connection);
+        }
+    }
+
+    impl Aged for LruStatsCache{
+        fn age(&self, handle: &str, by: Duration){
+            lock(&self.entries).peek_mut(handle).unwrap().2 -= by; //recency is the list order, only the write time is kept
+        }
+        fn poison(&self){
+            poison(&self.entries);
+        }
+    }
+
+    ///a directory of its own under the system temp dir, removed with everything in it on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir{
+        fn new() -> Self{
+            let dir = temp_path(&std::env::temp_dir(), "cache-test");
+            create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir{
+        fn drop(&mut self){
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn entry(players: &[&str]) -> CacheEntry{
+        CacheEntry{ stamp: CacheStamp::current(), players: players.iter().map(|name|(name.to_string(), CumulativePlacementStats::default())).collect() }
+    }
+
+    fn round_trips_and_misses(cache: &dyn StatsCache){
+        assert!(cache.get("replay").is_none());
+        cache.set("replay", &entry(&["muncher", "other"]));
+        let cached = cache.get("replay").expect("just set");
+        assert_eq!(cached.stamp, CacheStamp::current());
+        let mut names: Vec<_> = cached.players.keys().collect();
+        names.sort();
+        assert_eq!(names, ["muncher", "other"]);
+
+        cache.set("replay", &entry(&["muncher"]));
+        assert_eq!(cache.get("replay").unwrap().players.len(), 1); //replaced, not merged
+        assert!(cache.get("another replay").is_none());
+        assert_eq!(cache.usage().entries, 1);
+    }
+
+    fn evicts_the_least_recently_read(cache: &(impl StatsCache + Aged)){
+        for (handle, age) in [("a", 30), ("b", 20), ("c", 10)]{
+            cache.set(handle, &entry(&["muncher"]));
+            cache.age(handle, Duration::from_secs(age));
+        }
+        assert!(cache.get("a").is_some()); //read last, so b is now the longest unread
+
+        let limits = CacheLimits{ max_entries: 2, ..CacheLimits::default() };
+        assert_eq!(cache.evict(&limits), 1);
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some() && cache.get("c").is_some());
+        assert_eq!(cache.usage().entries, 2);
+        assert_eq!(cache.evict(&limits), 0);
+    }
+
+    fn evicts_past_the_ttl(cache: &(impl StatsCache + Aged)){
+        cache.set("old", &entry(&["muncher"]));
+        cache.set("new", &entry(&["muncher"]));
+        cache.age("old", Duration::from_secs(120));
+        let limits = CacheLimits{ ttl: Duration::from_secs(60), ..CacheLimits::default() };
+        assert_eq!(cache.evict(&limits), 1);
+        assert!(cache.get("old").is_none());
+        assert!(cache.get("new").is_some());
+    }
+
+    fn evicts_past_the_max_bytes(cache: &(impl StatsCache + Aged)){
+        cache.set("old", &entry(&["muncher"]));
+        cache.set("new", &entry(&["muncher"]));
+        cache.age("old", Duration::from_secs(10));
+        cache.age("new", Duration::from_secs(5));
+        let bytes = cache.usage().bytes;
+        assert!(bytes > 0);
+        let limits = CacheLimits{ max_bytes: bytes - 1, ..CacheLimits::default() };
+        assert_eq!(cache.evict(&limits), 1);
+        assert!(cache.get("old").is_none());
+        assert_eq!(cache.usage().bytes, bytes / 2); //both entries are the same size
+        assert_eq!(cache.evict(&CacheLimits{ max_bytes: 0, ..CacheLimits::default() }), 1);
+        assert_eq!(cache.usage().entries, 0);
+    }
+
+    fn survives_a_poisoned_lock(cache: &(impl StatsCache + Aged)){
+        cache.set("replay", &entry(&["muncher"]));
+        cache.poison();
+        assert!(cache.get("replay").is_some());
+        cache.set("another", &entry(&["muncher"]));
+        assert_eq!(cache.usage().entries, 2);
+        assert_eq!(cache.evict(&CacheLimits{ max_entries: 1, ..CacheLimits::default() }), 1);
+        assert_eq!(cache.count_stale(&CacheStamp::current()), 0);
+    }
+
+    #[test]
+    fn evict_enforces_every_limit(){
+        let dirs: Vec<_> = (0..6).map(|_|TempDir::new()).collect();
+        evicts_past_the_ttl(&FsCache::open(&dirs[0].0));
+        evicts_past_the_max_bytes(&FsCache::open(&dirs[1].0));
+        survives_a_poisoned_lock(&FsCache::open(&dirs[2].0));
+
+        let sqlite = |dir: &TempDir|SqliteCache::open(dir.0.join("stats.sqlite3")).unwrap();
+        evicts_past_the_ttl(&sqlite(&dirs[3]));
+        evicts_past_the_max_bytes(&sqlite(&dirs[4]));
+        survives_a_poisoned_lock(&sqlite(&dirs[5]));
+
+        evicts_past_the_ttl(&LruStatsCache::new(10));
+        evicts_past_the_max_bytes(&LruStatsCache::new(10));
+        survives_a_poisoned_lock(&LruStatsCache::new(10));
+    }
+
+    #[test]
+    fn fs_cache(){
+        let dir = TempDir::new();
+        round_trips_and_misses(&FsCache::open(&dir.0));
+        let reopened = FsCache::open(&dir.0);
+        assert_eq!(reopened.usage().entries, 1); //indexed from the directory
+        assert!(reopened.get("replay").is_some());
+        let dir = TempDir::new();
+        evicts_the_least_recently_read(&FsCache::open(&dir.0));
+    }
+
+    #[test]
+    fn sqlite_cache(){
+        let dir = TempDir::new();
+        round_trips_and_misses(&SqliteCache::open(dir.0.join("stats.sqlite3")).unwrap());
+        evicts_the_least_recently_read(&SqliteCache::open(dir.0.join("evicted.sqlite3")).unwrap());
+    }
+
+    #[test]
+    fn lru_cache(){
+        round_trips_and_misses(&LruStatsCache::new(10));
+        evicts_the_least_recently_read(&LruStatsCache::new(10));
+        let full = LruStatsCache::new(1);
+        full.set("a", &entry(&["muncher"]));
+        full.set("b", &entry(&["muncher"]));
+        assert!(full.get("a").is_none()); //past capacity the oldest goes right away
+        assert_eq!(full.usage().entries, 1);
+    }
+
+    #[test]
+    fn fs_cache_refuses_handles_that_leave_its_directory(){
+        let dir = TempDir::new();
+        let cache = FsCache::open(dir.0.join("cache"));
+        for handle in ["../escaped", "nested/replay", "nested\\replay", ".hidden", ""]{
+            cache.set(handle, &entry(&["muncher"]));
+            assert!(cache.get(handle).is_none(), "{handle}");
         }
+        assert_eq!(cache.usage().entries, 0);
+        assert!(!dir.0.join("escaped").exists());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,1032 @@
+use std::collections::VecDeque;
+
+use crate::attack::{get_clear_attack, get_surge_attack};
+use crate::replay_response::{Board, ClearType, MinoType, PlacementStats, SpinRules};
+use crate::ruleset::Ruleset;
+
+///every board is stored as tetrio's 10x40. replays, `Board`, bitris' `Board64` and the analyzers
+///all assume it, rulesets that don't fit are rejected with `Ruleset::fits_board`
+pub const BOARD_WIDTH: usize = 10;
+pub const BOARD_HEIGHT: usize = 40;
+
+const BAG: [MinoType; 7] = [
+    MinoType::Z,
+    MinoType::L,
+    MinoType::O,
+    MinoType::S,
+    MinoType::I,
+    MinoType::J,
+    MinoType::T,
+];
+
+///park-miller lcg, the same generator tetrio seeds its bags with
+#[derive(Debug, Clone)]
+pub struct Rng {
+    t: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut t = seed % 2147483647;
+        if t == 0 {
+            t += 2147483646;
+        }
+        Self { t }
+    }
+    pub fn next(&mut self) -> u64 {
+        self.t = 16807 * self.t % 2147483647;
+        self.t
+    }
+    pub fn next_float(&mut self) -> f64 {
+        (self.next() - 1) as f64 / 2147483646.0
+    }
+    ///fisher yates shuffle, iterates from the back like tetrio does
+    pub fn shuffle<T>(&mut self, array: &mut [T]) {
+        for i in (1..array.len()).rev() {
+            let r = (self.next_float() * (i + 1) as f64).floor() as usize;
+            array.swap(i, r);
+        }
+    }
+    pub fn next_bag(&mut self) -> [MinoType; 7] {
+        let mut bag = BAG;
+        self.shuffle(&mut bag);
+        bag
+    }
+}
+
+///cells of a piece in north orientation, relative to the bottom left of its bounding box, y up
+fn north_cells(shape: MinoType) -> ([(i32, i32); 4], i32) {
+    match shape {
+        MinoType::Z => ([(0, 2), (1, 2), (1, 1), (2, 1)], 3),
+        MinoType::S => ([(1, 2), (2, 2), (0, 1), (1, 1)], 3),
+        MinoType::L => ([(2, 2), (0, 1), (1, 1), (2, 1)], 3),
+        MinoType::J => ([(0, 2), (0, 1), (1, 1), (2, 1)], 3),
+        MinoType::T => ([(1, 2), (0, 1), (1, 1), (2, 1)], 3),
+        MinoType::I => ([(0, 2), (1, 2), (2, 2), (3, 2)], 4),
+        _ => ([(0, 0), (1, 0), (0, 1), (1, 1)], 2),
+    }
+}
+
+///cells of a piece after rotating clockwise `rotation` times inside its bounding box
+pub fn piece_cells(shape: MinoType, rotation: u8) -> [(i32, i32); 4] {
+    let (mut cells, size) = north_cells(shape);
+    for _ in 0..(rotation % 4) {
+        for cell in cells.iter_mut() {
+            *cell = (cell.1, size - 1 - cell.0);
+        }
+    }
+    cells
+}
+
+const JLSTZ_KICKS: [[(i32, i32); 5]; 8] = [
+    [(0, 0), (-1, 0), (-1, 1), (0, -2), (-1, -2)], // 0->R
+    [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)],     // R->0
+    [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)],     // R->2
+    [(0, 0), (-1, 0), (-1, 1), (0, -2), (-1, -2)], // 2->R
+    [(0, 0), (1, 0), (1, 1), (0, -2), (1, -2)],    // 2->L
+    [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)],  // L->2
+    [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)],  // L->0
+    [(0, 0), (1, 0), (1, 1), (0, -2), (1, -2)],    // 0->L
+];
+
+const I_KICKS: [[(i32, i32); 5]; 8] = [
+    [(0, 0), (1, 0), (-2, 0), (-2, -1), (1, 2)], // 0->R
+    [(0, 0), (-1, 0), (2, 0), (-1, -2), (2, 1)], // R->0
+    [(0, 0), (-1, 0), (2, 0), (-1, 2), (2, -1)], // R->2
+    [(0, 0), (-2, 0), (1, 0), (-2, 1), (1, -2)], // 2->R
+    [(0, 0), (2, 0), (-1, 0), (2, 1), (-1, -2)], // 2->L
+    [(0, 0), (1, 0), (-2, 0), (1, 2), (-2, -1)], // L->2
+    [(0, 0), (1, 0), (-2, 0), (1, -2), (-2, 1)], // L->0
+    [(0, 0), (-1, 0), (2, 0), (2, -1), (-1, 2)], // 0->L
+];
+
+const FLIP_KICKS: [[(i32, i32); 6]; 4] = [
+    [(0, 0), (0, 1), (1, 1), (-1, 1), (1, 0), (-1, 0)], // 0->2
+    [(0, 0), (1, 0), (1, 2), (1, 1), (0, 2), (0, 1)],   // R->L
+    [(0, 0), (0, -1), (-1, -1), (1, -1), (-1, 0), (1, 0)], // 2->0
+    [(0, 0), (-1, 0), (-1, 2), (-1, 1), (0, 2), (0, 1)], // L->R
+];
+
+///srs+ kick offsets for rotating from `from` by `turns` clockwise quarter turns
+pub fn kicks(shape: MinoType, from: u8, turns: u8) -> Vec<(i32, i32)> {
+    let from = from % 4;
+    match (shape, turns % 4) {
+        (MinoType::O, _) => vec![(0, 0)],
+        (MinoType::I, 2) => vec![(0, 0)],
+        (_, 2) => FLIP_KICKS[from as usize].to_vec(),
+        (shape, turns) => {
+            let index = if turns == 1 {
+                (from * 2) as usize
+            } else {
+                ((from + 3) % 4 * 2 + 1) as usize
+            };
+            if shape == MinoType::I {
+                I_KICKS[index].to_vec()
+            } else {
+                JLSTZ_KICKS[index].to_vec()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActivePiece {
+    pub shape: MinoType,
+    pub rotation: u8,
+    pub x: i32,
+    pub y: i32,
+}
+
+impl ActivePiece {
+    ///spawns the piece above the `height` visible rows, centered like tetrio does
+    pub fn spawn(shape: MinoType, height: usize) -> Self {
+        let height = height as i32;
+        let (x, y) = match shape {
+            MinoType::O => (4, height),
+            MinoType::I => (3, height - 2),
+            _ => (3, height - 1),
+        };
+        Self {
+            shape,
+            rotation: 0,
+            x,
+            y,
+        }
+    }
+    pub fn cells(&self) -> [(i32, i32); 4] {
+        let mut cells = piece_cells(self.shape, self.rotation);
+        for cell in cells.iter_mut() {
+            cell.0 += self.x;
+            cell.1 += self.y;
+        }
+        cells
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Spin {
+    None,
+    Mini,
+    Full,
+}
+
+///a 10 wide playfield stored bottom up
+#[derive(Debug, Clone)]
+pub struct Field {
+    rows: Vec<[MinoType; BOARD_WIDTH]>,
+}
+
+impl Default for Field {
+    fn default() -> Self {
+        Self {
+            rows: vec![[MinoType::Empty; BOARD_WIDTH]; BOARD_HEIGHT],
+        }
+    }
+}
+
+impl Field {
+    ///walls and floor count as filled, the area above the board does not
+    pub fn is_filled(&self, x: i32, y: i32) -> bool {
+        if x < 0 || x >= BOARD_WIDTH as i32 || y < 0 {
+            return true;
+        }
+        if y >= BOARD_HEIGHT as i32 {
+            return false;
+        }
+        self.rows[y as usize][x as usize] != MinoType::Empty
+    }
+    pub fn collides(&self, piece: &ActivePiece) -> bool {
+        piece.cells().iter().any(|&(x, y)| self.is_filled(x, y))
+    }
+    ///the piece rotated by `turns` clockwise quarter turns and the index of the kick used
+    pub fn rotate(&self, piece: &ActivePiece, turns: u8) -> Option<(ActivePiece, usize)> {
+        let rotation = (piece.rotation + turns) % 4;
+        kicks(piece.shape, piece.rotation, turns)
+            .into_iter()
+            .map(|(dx, dy)| ActivePiece {
+                rotation,
+                x: piece.x + dx,
+                y: piece.y + dy,
+                ..*piece
+            })
+            .enumerate()
+            .find(|(_, rotated)| !self.collides(rotated))
+            .map(|(kick, rotated)| (rotated, kick))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.rows
+            .iter()
+            .all(|row| row.iter().all(|&mino| mino == MinoType::Empty))
+    }
+    ///writes the piece into the field, returns false if any cell is out of the board
+    pub fn lock(&mut self, piece: &ActivePiece) -> bool {
+        let mut in_bounds = true;
+        for (x, y) in piece.cells() {
+            if y >= BOARD_HEIGHT as i32 {
+                in_bounds = false;
+                continue;
+            }
+            self.rows[y as usize][x as usize] = piece.shape;
+        }
+        in_bounds
+    }
+    ///removes full rows, returns (lines cleared, garbage lines cleared)
+    pub fn clear_lines(&mut self) -> (usize, usize) {
+        let mut lines = 0;
+        let mut garbage = 0;
+        self.rows.retain(|row| {
+            if row.iter().all(|&mino| mino != MinoType::Empty) {
+                lines += 1;
+                if row.contains(&MinoType::Garbage) {
+                    garbage += 1;
+                }
+                false
+            } else {
+                true
+            }
+        });
+        self.rows
+            .resize(BOARD_HEIGHT, [MinoType::Empty; BOARD_WIDTH]);
+        (lines, garbage)
+    }
+    ///pushes garbage rows in from the bottom, returns false if blocks were pushed off the top
+    pub fn insert_garbage(&mut self, column: usize) -> bool {
+        let mut row = [MinoType::Garbage; BOARD_WIDTH];
+        row[column.min(BOARD_WIDTH - 1)] = MinoType::Empty;
+        self.rows.insert(0, row);
+        let top = self.rows.pop().unwrap_or([MinoType::Empty; BOARD_WIDTH]);
+        top.iter().all(|&mino| mino == MinoType::Empty)
+    }
+    ///3 corner t-spin check, the front corners decide between full and mini. only counts for a t
+    ///whose last move was a rotation, like every spin. under all-mini other pieces that were
+    ///rotated into a spot they can't move out of are minis, without spin rules nothing is a spin
+    pub fn spin(
+        &self,
+        piece: &ActivePiece,
+        last_kick: Option<usize>,
+        spin_rules: SpinRules,
+    ) -> Spin {
+        if spin_rules == SpinRules::None {
+            return Spin::None;
+        }
+        if piece.shape != MinoType::T {
+            let immobile = [(-1, 0), (1, 0), (0, 1)].iter().all(|&(dx, dy)| {
+                self.collides(&ActivePiece {
+                    x: piece.x + dx,
+                    y: piece.y + dy,
+                    ..*piece
+                })
+            });
+            return if spin_rules == SpinRules::AllMini && last_kick.is_some() && immobile {
+                Spin::Mini
+            } else {
+                Spin::None
+            };
+        }
+        if last_kick.is_none() {
+            return Spin::None;
+        }
+        let corners = [(0, 2), (2, 2), (2, 0), (0, 0)]; //clockwise from top left
+        let filled: Vec<bool> = corners
+            .iter()
+            .map(|&(dx, dy)| self.is_filled(piece.x + dx, piece.y + dy))
+            .collect();
+        if filled.iter().filter(|&&f| f).count() < 3 {
+            return Spin::None;
+        }
+        let front = piece.rotation as usize % 4;
+        if (filled[front] && filled[(front + 1) % 4]) || last_kick == Some(4) {
+            Spin::Full
+        } else {
+            Spin::Mini
+        }
+    }
+    ///reads the replay response layout, top row first
+    pub fn from_board(board: &Board) -> Self {
+        let mut field = Self::default();
+        for (i, &mino) in board.iter().enumerate() {
+            let y = BOARD_HEIGHT - 1 - i / BOARD_WIDTH;
+            field.rows[y][i % BOARD_WIDTH] = mino;
+        }
+        field
+    }
+    ///converts to the replay response layout, top row first
+    pub fn to_board(&self) -> Board {
+        self.rows
+            .iter()
+            .rev()
+            .flat_map(|row| row.iter().copied())
+            .collect()
+    }
+}
+
+///clear type reported for a placement, spins by pieces other than t are all-spins
+pub fn classify_clear(lines: usize, spin: Spin, t_piece: bool) -> ClearType {
+    match (spin, lines) {
+        (Spin::None, 0) => ClearType::None,
+        (Spin::None, 1) => ClearType::Single,
+        (Spin::None, 2) => ClearType::Double,
+        (Spin::None, 3) => ClearType::Triple,
+        (Spin::None, 4) => ClearType::Quad,
+        (Spin::None, _) => ClearType::Penta,
+        (_, 0) if !t_piece => ClearType::Allspin,
+        (_, 1) if !t_piece => ClearType::AllspinSingle,
+        (_, 2) if !t_piece => ClearType::AllspinDouble,
+        (_, 3) if !t_piece => ClearType::AllspinTriple,
+        (_, _) if !t_piece => ClearType::AllspinQuad,
+        (Spin::Mini, 0) => ClearType::TspinMini,
+        (Spin::Full, 0) => ClearType::Tspin,
+        (Spin::Mini, 1) => ClearType::TspinMiniSingle,
+        (Spin::Full, 1) => ClearType::TspinSingle,
+        (Spin::Mini, 2) => ClearType::TspinMiniDouble,
+        (Spin::Full, 2) => ClearType::TspinDouble,
+        (_, 3) => ClearType::TspinTriple,
+        (_, 4) => ClearType::TspinQuad,
+        (_, _) => ClearType::TspinPenta,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Handling {
+    pub arr: f64,
+    pub das: f64,
+    pub sdf: f64,
+}
+
+impl Default for Handling {
+    fn default() -> Self {
+        Self {
+            arr: 2.0,
+            das: 10.0,
+            sdf: 6.0,
+        }
+    }
+}
+
+///the subset of tetrio game options that affect placements
+#[derive(Debug, Clone)]
+pub struct GameOptions {
+    pub seed: u64,
+    pub gravity: f64,
+    pub gravity_increase: f64,
+    pub gravity_margin: f64,
+    pub lock_time: f64,
+    pub lock_resets: usize,
+    pub garbage_speed: f64,
+    pub garbage_cap: usize,
+    pub messiness_change: f64,
+    pub messiness_inner: f64,
+    pub next_count: usize,
+    pub handling: Handling,
+    pub ruleset: Ruleset,
+}
+
+impl Default for GameOptions {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            gravity: 0.02,
+            gravity_increase: 0.0,
+            gravity_margin: 0.0,
+            lock_time: 30.0,
+            lock_resets: 15,
+            garbage_speed: 20.0,
+            garbage_cap: 8,
+            messiness_change: 1.0,
+            messiness_inner: 0.0,
+            next_count: 5,
+            handling: Handling::default(),
+            ruleset: Ruleset::tetrio_s1(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Key {
+    MoveLeft,
+    MoveRight,
+    SoftDrop,
+    RotateCw,
+    RotateCcw,
+    Rotate180,
+    HardDrop,
+    Hold,
+}
+
+impl Key {
+    pub fn parse(key: &str) -> Option<Self> {
+        match key {
+            "moveLeft" => Some(Key::MoveLeft),
+            "moveRight" => Some(Key::MoveRight),
+            "softDrop" => Some(Key::SoftDrop),
+            "rotateCW" => Some(Key::RotateCw),
+            "rotateCCW" => Some(Key::RotateCcw),
+            "rotate180" => Some(Key::Rotate180),
+            "hardDrop" => Some(Key::HardDrop),
+            "hold" => Some(Key::Hold),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IncomingGarbage {
+    amount: usize,
+    column: Option<usize>,
+    ready_at: f64,
+}
+
+///replays the input stream of one player for one game and records every placement
+pub struct Game {
+    opts: GameOptions,
+    field: Field,
+    rng: Rng,
+    garbage_rng: Rng,
+    queue: VecDeque<MinoType>,
+    hold: Option<MinoType>,
+    hold_locked: bool,
+    piece: Option<ActivePiece>,
+    last_kick: Option<usize>, //kick index of the last successful rotation, none if the piece moved since
+
+    time: f64,
+    gravity: f64,
+    gravity_progress: f64,
+    lock_timer: f64,
+    lock_resets: usize,
+
+    left_held: bool,
+    right_held: bool,
+    direction: i32,
+    das_charge: f64,
+    arr_progress: f64,
+    soft_drop: bool,
+
+    combo: usize,
+    btb: usize,
+    garbage_queue: VecDeque<IncomingGarbage>,
+    garbage_column: Option<usize>,
+    attack_received: Vec<usize>,
+    garbage_columns: Vec<Option<usize>>,
+
+    keypresses: usize,
+    last_placement: f64,
+    placements: Vec<PlacementStats>,
+    topped_out: bool,
+}
+
+impl Game {
+    pub fn new(opts: GameOptions) -> Self {
+        let mut game = Self {
+            field: Field::default(),
+            rng: Rng::new(opts.seed),
+            garbage_rng: Rng::new(opts.seed),
+            queue: VecDeque::new(),
+            hold: None,
+            hold_locked: false,
+            piece: None,
+            last_kick: None,
+            time: 0.0,
+            gravity: opts.gravity,
+            gravity_progress: 0.0,
+            lock_timer: 0.0,
+            lock_resets: 0,
+            left_held: false,
+            right_held: false,
+            direction: 0,
+            das_charge: 0.0,
+            arr_progress: 0.0,
+            soft_drop: false,
+            combo: 0,
+            btb: 0,
+            garbage_queue: VecDeque::new(),
+            garbage_column: None,
+            attack_received: Vec::new(),
+            garbage_columns: Vec::new(),
+            keypresses: 0,
+            last_placement: 0.0,
+            placements: Vec::new(),
+            topped_out: false,
+            opts,
+        };
+        game.spawn_next();
+        game
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.topped_out
+    }
+
+    pub fn into_placements(self) -> Vec<PlacementStats> {
+        self.placements
+    }
+
+    ///steps gravity, autoshift and lock delay up to the given frame
+    pub fn advance_to(&mut self, time: f64) {
+        while self.time < time && !self.topped_out {
+            let dt = (time - self.time).min(1.0);
+            self.tick(dt);
+            self.time += dt;
+        }
+    }
+
+    fn tick(&mut self, dt: f64) {
+        if self.time >= self.opts.gravity_margin {
+            self.gravity += self.opts.gravity_increase * dt;
+        }
+        if self.piece.is_none() {
+            return;
+        }
+
+        if self.direction != 0 {
+            self.das_charge += dt;
+            let charged = self.das_charge - self.opts.handling.das;
+            if charged >= 0.0 {
+                if self.opts.handling.arr <= 0.0 {
+                    while self.shift(self.direction) {}
+                } else {
+                    self.arr_progress += charged.min(dt);
+                    while self.arr_progress >= self.opts.handling.arr {
+                        self.arr_progress -= self.opts.handling.arr;
+                        if !self.shift(self.direction) {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let rate = if self.soft_drop {
+            if self.opts.handling.sdf >= 41.0 {
+                f64::INFINITY
+            } else {
+                (self.gravity * self.opts.handling.sdf).max(self.opts.handling.sdf / 20.0)
+            }
+        } else {
+            self.gravity
+        };
+        if rate >= 20.0 {
+            while self.drop() {}
+        } else {
+            self.gravity_progress += rate * dt;
+            while self.gravity_progress >= 1.0 {
+                self.gravity_progress -= 1.0;
+                if !self.drop() {
+                    self.gravity_progress = 0.0;
+                    break;
+                }
+            }
+        }
+
+        if self.is_grounded() {
+            self.lock_timer += dt;
+            if self.lock_timer >= self.opts.lock_time {
+                self.place();
+            }
+        }
+    }
+
+    pub fn key_down(&mut self, key: Key) {
+        self.keypresses += 1;
+        match key {
+            Key::MoveLeft => {
+                self.left_held = true;
+                self.set_direction(-1);
+            }
+            Key::MoveRight => {
+                self.right_held = true;
+                self.set_direction(1);
+            }
+            Key::SoftDrop => self.soft_drop = true,
+            Key::RotateCw => self.rotate(1),
+            Key::RotateCcw => self.rotate(3),
+            Key::Rotate180 => self.rotate(2),
+            Key::HardDrop => {
+                if self.piece.is_some() {
+                    while self.drop() {}
+                    self.place();
+                }
+            }
+            Key::Hold => self.swap_hold(),
+        }
+    }
+
+    pub fn key_up(&mut self, key: Key) {
+        match key {
+            Key::MoveLeft => {
+                self.left_held = false;
+                if self.direction == -1 {
+                    self.release_direction(self.right_held, 1);
+                }
+            }
+            Key::MoveRight => {
+                self.right_held = false;
+                if self.direction == 1 {
+                    self.release_direction(self.left_held, -1);
+                }
+            }
+            Key::SoftDrop => self.soft_drop = false,
+            _ => {}
+        }
+    }
+
+    ///queues incoming garbage, it becomes placeable after the garbage speed has elapsed
+    pub fn receive_garbage(&mut self, amount: usize, column: Option<usize>) {
+        if amount == 0 {
+            return;
+        }
+        self.attack_received.push(amount);
+        self.garbage_columns.push(column);
+        self.garbage_queue.push_back(IncomingGarbage {
+            amount,
+            column,
+            ready_at: self.time + self.opts.garbage_speed,
+        });
+    }
+
+    fn set_direction(&mut self, direction: i32) {
+        self.direction = direction;
+        self.das_charge = 0.0;
+        self.arr_progress = 0.0;
+        self.shift(direction);
+    }
+
+    fn release_direction(&mut self, other_held: bool, other: i32) {
+        self.das_charge = 0.0;
+        self.arr_progress = 0.0;
+        self.direction = if other_held { other } else { 0 };
+    }
+
+    fn try_move(&mut self, dx: i32, dy: i32) -> bool {
+        let Some(piece) = self.piece else {
+            return false;
+        };
+        let moved = ActivePiece {
+            x: piece.x + dx,
+            y: piece.y + dy,
+            ..piece
+        };
+        if self.field.collides(&moved) {
+            return false;
+        }
+        self.piece = Some(moved);
+        self.last_kick = None;
+        true
+    }
+
+    fn shift(&mut self, direction: i32) -> bool {
+        let moved = self.try_move(direction, 0);
+        if moved {
+            self.reset_lock();
+        }
+        moved
+    }
+
+    fn drop(&mut self) -> bool {
+        let moved = self.try_move(0, -1);
+        if moved {
+            self.lock_timer = 0.0;
+        }
+        moved
+    }
+
+    fn rotate(&mut self, turns: u8) {
+        let Some(piece) = self.piece else {
+            return;
+        };
+        if let Some((rotated, kick)) = self.field.rotate(&piece, turns) {
+            self.piece = Some(rotated);
+            self.last_kick = Some(if turns == 2 { 0 } else { kick });
+            self.reset_lock();
+        }
+    }
+
+    fn reset_lock(&mut self) {
+        if self.lock_resets < self.opts.lock_resets {
+            self.lock_timer = 0.0;
+            self.lock_resets += 1;
+        }
+    }
+
+    fn is_grounded(&self) -> bool {
+        match self.piece {
+            Some(piece) => self.field.collides(&ActivePiece {
+                y: piece.y - 1,
+                ..piece
+            }),
+            None => false,
+        }
+    }
+
+    fn swap_hold(&mut self) {
+        if self.hold_locked {
+            return;
+        }
+        let Some(piece) = self.piece.take() else {
+            return;
+        };
+        match self.hold.replace(piece.shape) {
+            Some(held) => self.spawn(held),
+            None => self.spawn_next(),
+        }
+        self.hold_locked = true;
+    }
+
+    fn spawn_next(&mut self) {
+        while self.queue.len() < 14 {
+            let bag = self.rng.next_bag();
+            self.queue.extend(bag);
+        }
+        if let Some(shape) = self.queue.pop_front() {
+            self.spawn(shape);
+        }
+    }
+
+    fn spawn(&mut self, shape: MinoType) {
+        let mut piece = ActivePiece::spawn(shape, self.opts.ruleset.height);
+        if self.field.collides(&piece) {
+            piece.y += 1;
+            if self.field.collides(&piece) {
+                self.topped_out = true;
+                return;
+            }
+        }
+        self.piece = Some(piece);
+        self.last_kick = None;
+        self.lock_timer = 0.0;
+        self.lock_resets = 0;
+        self.gravity_progress = 0.0;
+        if self.direction != 0
+            && self.das_charge >= self.opts.handling.das
+            && self.opts.handling.arr <= 0.0
+        {
+            while self.shift(self.direction) {}
+        }
+    }
+
+    fn place(&mut self) {
+        let Some(piece) = self.piece.take() else {
+            return;
+        };
+        let spin = self
+            .field
+            .spin(&piece, self.last_kick, self.opts.ruleset.spin_rules);
+        if !self.field.lock(&piece) {
+            self.topped_out = true;
+        }
+        let (lines_cleared, garbage_cleared) = self.field.clear_lines();
+        let clear_type = classify_clear(lines_cleared, spin, piece.shape == MinoType::T);
+        let btb_clear = lines_cleared > 0 && (lines_cleared >= 4 || spin != Spin::None);
+
+        let mut attack = 0;
+        if lines_cleared > 0 {
+            attack = get_clear_attack(
+                &self.opts.ruleset,
+                clear_type,
+                self.combo,
+                self.btb,
+                self.field.is_empty(),
+            );
+            self.combo += 1;
+            if btb_clear {
+                self.btb += 1;
+            } else {
+                attack += get_surge_attack(&self.opts.ruleset, self.btb.saturating_sub(1));
+                self.btb = 0;
+            }
+        } else {
+            self.combo = 0;
+        }
+
+        let mut cancelling = attack;
+        while cancelling > 0 {
+            let Some(front) = self.garbage_queue.front_mut() else {
+                break;
+            };
+            let cancelled = front.amount.min(cancelling);
+            front.amount -= cancelled;
+            cancelling -= cancelled;
+            if front.amount == 0 {
+                self.garbage_queue.pop_front();
+            }
+        }
+
+        let mut attack_tanked = Vec::new();
+        if lines_cleared == 0 {
+            let mut cap = self.opts.garbage_cap;
+            while cap > 0 {
+                let Some(front) = self.garbage_queue.front_mut() else {
+                    break;
+                };
+                if front.ready_at > self.time {
+                    break;
+                }
+                let tanked = front.amount.min(cap);
+                let column = front.column;
+                front.amount -= tanked;
+                cap -= tanked;
+                if front.amount == 0 {
+                    self.garbage_queue.pop_front();
+                }
+                self.tank(tanked, column);
+                attack_tanked.push(tanked);
+            }
+        }
+
+        self.placements.push(PlacementStats {
+            shape: piece.shape,
+            lines_cleared,
+            garbage_cleared,
+            keypresses: self.keypresses,
+            attack: if attack > 0 { vec![attack] } else { Vec::new() },
+            clear_type,
+            combo: self.combo,
+            btb_chain: self.btb,
+            btb_clear,
+            frame_delay: self.time - self.last_placement,
+            attack_received: std::mem::take(&mut self.attack_received),
+            garbage_columns: std::mem::take(&mut self.garbage_columns),
+            attack_tanked,
+            board: self.field.to_board(),
+            queue: Vec::new(),
+            ruleset: self.opts.ruleset,
+        });
+        self.keypresses = 0;
+        self.last_placement = self.time;
+        self.hold_locked = false;
+
+        if !self.topped_out {
+            self.spawn_next();
+        }
+        let queue = self.preview();
+        if let Some(placement) = self.placements.last_mut() {
+            placement.queue = queue;
+        }
+    }
+
+    ///pushes up garbage lines, picking hole columns with tetrio's messiness rules
+    fn tank(&mut self, amount: usize, column: Option<usize>) {
+        let mut column = match (column, self.garbage_column) {
+            (Some(column), _) => column,
+            (None, Some(previous))
+                if self.garbage_rng.next_float() >= self.opts.messiness_change =>
+            {
+                previous
+            }
+            _ => self.random_column(),
+        };
+        for i in 0..amount {
+            if i > 0 && self.garbage_rng.next_float() < self.opts.messiness_inner {
+                column = self.random_column();
+            }
+            if !self.field.insert_garbage(column) {
+                self.topped_out = true;
+            }
+        }
+        self.garbage_column = Some(column);
+    }
+
+    fn random_column(&mut self) -> usize {
+        ((self.garbage_rng.next_float() * BOARD_WIDTH as f64).floor() as usize).min(BOARD_WIDTH - 1)
+    }
+
+    ///hold first (empty if nothing is held), then the active piece, then the visible next queue
+    fn preview(&self) -> Vec<MinoType> {
+        let mut queue = vec![self.hold.unwrap_or(MinoType::Empty)];
+        if let Some(piece) = self.piece {
+            queue.push(piece.shape);
+        }
+        queue.extend(self.queue.iter().take(self.opts.next_count));
+        queue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board_analyzer::board_from_rows;
+
+    fn press(game: &mut Game, key: Key) {
+        game.key_down(key);
+        game.key_up(key);
+    }
+
+    #[test]
+    fn plain_clear_after_b2b_keeps_its_attack() {
+        let mut game = Game::new(GameOptions::default());
+        game.field = Field::from_board(&board_from_rows(&[
+            "#####.####",
+            "#####.####",
+            "#####.####",
+            "#####.####",
+            "..########",
+            "..########",
+            "#########.",
+        ]));
+        game.piece = Some(ActivePiece::spawn(MinoType::I, game.opts.ruleset.height));
+        game.queue = VecDeque::from([MinoType::O]);
+
+        press(&mut game, Key::RotateCw);
+        press(&mut game, Key::HardDrop);
+        for _ in 0..4 {
+            press(&mut game, Key::MoveLeft);
+        }
+        press(&mut game, Key::HardDrop);
+
+        let placements = game.into_placements();
+        assert_eq!(placements[0].clear_type, ClearType::Quad);
+        assert_eq!(placements[0].attack, vec![4]);
+        assert_eq!(placements[1].clear_type, ClearType::Double);
+        assert!(!placements[1].btb_clear);
+        assert_eq!(placements[1].attack, vec![1]);
+    }
+
+    #[test]
+    fn t_shifted_into_three_corners_is_no_spin() {
+        let mut game = Game::new(GameOptions::default());
+        game.field = Field::from_board(&board_from_rows(&[".#########"]));
+        game.piece = Some(ActivePiece::spawn(MinoType::T, game.opts.ruleset.height));
+        game.queue = VecDeque::from([MinoType::O]);
+
+        press(&mut game, Key::RotateCw);
+        for _ in 0..4 {
+            press(&mut game, Key::MoveLeft);
+        }
+        press(&mut game, Key::HardDrop);
+        //the walls and the block under its point fill three corners, but it never rotated there
+
+        let placements = game.into_placements();
+        assert_eq!(placements[0].clear_type, ClearType::Single);
+        assert!(!placements[0].btb_clear);
+    }
+
+    #[test]
+    fn all_mini_spins_need_a_rotation_into_an_immobile_spot() {
+        let cases = [
+            (
+                vec!["...#.###..", ".#...###.#", "...#######", "#######.##"],
+                ActivePiece {
+                    shape: MinoType::S,
+                    rotation: 2,
+                    x: 1,
+                    y: 1,
+                },
+                ClearType::Allspin,
+            ),
+            (
+                vec!["#..#.#..#.", "##.###..##", "#######..#", "##.#######"],
+                ActivePiece {
+                    shape: MinoType::Z,
+                    rotation: 2,
+                    x: 6,
+                    y: 1,
+                },
+                ClearType::AllspinSingle,
+            ),
+            (
+                vec![".#..#.....", "########.#", "######...#"],
+                ActivePiece {
+                    shape: MinoType::L,
+                    rotation: 0,
+                    x: 6,
+                    y: -1,
+                },
+                ClearType::AllspinDouble,
+            ),
+            (
+                vec![
+                    "#.##...#..",
+                    "#####.####",
+                    "#####..###",
+                    "######.###",
+                    "######.###",
+                ],
+                ActivePiece {
+                    shape: MinoType::S,
+                    rotation: 3,
+                    x: 5,
+                    y: 1,
+                },
+                ClearType::AllspinTriple,
+            ),
+        ];
+        for (rows, piece, expected) in cases {
+            let field = Field::from_board(&board_from_rows(&rows));
+            assert!(!field.collides(&piece), "{expected:?}");
+            assert_eq!(field.spin(&piece, Some(2), SpinRules::AllMini), Spin::Mini);
+            assert_eq!(field.spin(&piece, None, SpinRules::AllMini), Spin::None);
+            assert_eq!(field.spin(&piece, Some(2), SpinRules::TSpins), Spin::None);
+
+            let mut placed = field.clone();
+            placed.lock(&piece);
+            let (lines, _) = placed.clear_lines();
+            assert_eq!(classify_clear(lines, Spin::Mini, false), expected);
+        }
+
+        let open = Field::default();
+        let mobile = ActivePiece {
+            y: 0,
+            ..ActivePiece::spawn(MinoType::S, Ruleset::default().height)
+        };
+        assert_eq!(open.spin(&mobile, Some(0), SpinRules::AllMini), Spin::None);
+    }
+}
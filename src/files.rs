@@ -0,0 +1,16 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+///a hidden file in `dir` to write `name` aside in, unique across threads and processes sharing the directory
+pub fn temp_path(dir: &Path, name: &str) -> PathBuf {
+    let n = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!(".{name}.{}.{n}.tmp", std::process::id()))
+}
+
+///whether a handle can name a file in a directory of them. separators could reach outside it
+///and a leading dot would pass for a temp file, or the parent directory
+pub fn is_file_handle(handle: &str) -> bool {
+    !handle.is_empty() && !handle.starts_with('.') && !handle.contains(['/', '\\'])
+}
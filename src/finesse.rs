@@ -0,0 +1,166 @@
+use std::collections::{HashSet, VecDeque};
+
+use bitris::prelude::*;
+
+use crate::engine::{ActivePiece, Field};
+use crate::replay_response::MinoType;
+
+///searches deeper than this are treated as unreachable, no sane placement needs that many keys
+const MAX_FINESSE_KEYS: usize = 12;
+
+///cells of a placement on the board it was placed on, y up
+pub fn placement_cells(placement: &BlPlacement) -> [(i32, i32); 4] {
+    placement
+        .locations()
+        .map(|location| (location.x, location.y))
+}
+
+fn sorted(mut cells: [(i32, i32); 4]) -> [(i32, i32); 4] {
+    cells.sort();
+    cells
+}
+
+fn hard_drop(field: &Field, mut piece: ActivePiece) -> ActivePiece {
+    loop {
+        let dropped = ActivePiece {
+            y: piece.y - 1,
+            ..piece
+        };
+        if field.collides(&dropped) {
+            return piece;
+        }
+        piece = dropped;
+    }
+}
+
+fn shift(field: &Field, piece: ActivePiece, dx: i32) -> Option<ActivePiece> {
+    let moved = ActivePiece {
+        x: piece.x + dx,
+        ..piece
+    };
+    (!field.collides(&moved)).then_some(moved)
+}
+
+fn das(field: &Field, mut piece: ActivePiece, dx: i32) -> Option<ActivePiece> {
+    let mut moved = false;
+    while let Some(shifted) = shift(field, piece, dx) {
+        piece = shifted;
+        moved = true;
+    }
+    moved.then_some(piece)
+}
+
+///orientations covering the same cells count as one, the lower of them. I, S and Z have two and O one
+pub fn canonical_rotation(shape: MinoType, rotation: u8) -> u8 {
+    match shape {
+        MinoType::O => 0,
+        MinoType::I | MinoType::S | MinoType::Z => rotation % 2,
+        _ => rotation % 4,
+    }
+}
+
+///fewest keypresses to get a piece from spawn above `height` rows into `target`, hard drop
+///included, along with the canonical rotation it lands in. taps, das to the wall, rotations and soft drop to the floor cost a key each
+pub fn optimal_keypresses(
+    field: &Field,
+    shape: MinoType,
+    height: usize,
+    target: [(i32, i32); 4],
+) -> Option<(usize, u8)> {
+    let target = sorted(target);
+    let mut spawn = ActivePiece::spawn(shape, height);
+    if field.collides(&spawn) {
+        spawn.y += 1;
+    }
+
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    visited.insert((spawn.rotation, spawn.x, spawn.y));
+    queue.push_back((spawn, 0));
+
+    while let Some((piece, keys)) = queue.pop_front() {
+        if sorted(hard_drop(field, piece).cells()) == target {
+            return Some((keys + 1, canonical_rotation(shape, piece.rotation)));
+        }
+        if keys + 1 >= MAX_FINESSE_KEYS {
+            continue;
+        }
+
+        let moves = [
+            shift(field, piece, -1),
+            shift(field, piece, 1),
+            das(field, piece, -1),
+            das(field, piece, 1),
+            field.rotate(&piece, 1).map(|(rotated, _)| rotated),
+            field.rotate(&piece, 3).map(|(rotated, _)| rotated),
+            field.rotate(&piece, 2).map(|(rotated, _)| rotated),
+            Some(hard_drop(field, piece)).filter(|dropped| *dropped != piece),
+        ];
+        for next in moves.into_iter().flatten() {
+            if visited.insert((next.rotation, next.x, next.y)) {
+                queue.push_back((next, keys + 1));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruleset::Ruleset;
+
+    fn keys(shape: MinoType, target: [(i32, i32); 4]) -> Option<(usize, u8)> {
+        optimal_keypresses(&Field::default(), shape, Ruleset::default().height, target)
+    }
+
+    #[test]
+    fn known_finesse_counts() {
+        let field = Field::default();
+        let spawn_drop = hard_drop(
+            &field,
+            ActivePiece::spawn(MinoType::T, Ruleset::default().height),
+        )
+        .cells();
+        assert_eq!(keys(MinoType::T, spawn_drop), Some((1, 0)));
+        assert_eq!(
+            keys(MinoType::T, [(2, 0), (3, 0), (4, 0), (3, 1)]),
+            Some((2, 0)) //one tap left
+        );
+        assert_eq!(
+            keys(MinoType::O, [(0, 0), (1, 0), (0, 1), (1, 1)]),
+            Some((2, 0))
+        );
+        assert_eq!(
+            keys(MinoType::I, [(0, 0), (1, 0), (2, 0), (3, 0)]),
+            Some((2, 0))
+        );
+        assert_eq!(
+            keys(MinoType::I, [(0, 0), (0, 1), (0, 2), (0, 3)]),
+            Some((3, 1)) //rotate, das and drop
+        );
+    }
+
+    #[test]
+    fn equivalent_orientations_share_a_rotation() {
+        for (shape, target) in [
+            (MinoType::S, [(8, 1), (8, 2), (9, 0), (9, 1)]),
+            (MinoType::Z, [(0, 0), (0, 1), (1, 1), (1, 2)]),
+            (MinoType::I, [(9, 0), (9, 1), (9, 2), (9, 3)]),
+        ] {
+            let (keys, rotation) = keys(shape, target).unwrap();
+            assert_eq!(keys, 3, "{shape:?}");
+            assert_eq!(rotation, 1, "{shape:?}");
+        }
+        for rotation in 0..4 {
+            assert_eq!(canonical_rotation(MinoType::O, rotation), 0);
+            assert_eq!(canonical_rotation(MinoType::S, rotation), rotation % 2);
+            assert_eq!(canonical_rotation(MinoType::T, rotation), rotation);
+        }
+    }
+
+    #[test]
+    fn floating_targets_have_no_count() {
+        assert_eq!(keys(MinoType::O, [(0, 5), (1, 5), (0, 6), (1, 6)]), None);
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::body::Body;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+
+use crate::board_analyzer::to_shape;
+use crate::engine::{BOARD_HEIGHT, BOARD_WIDTH};
+use crate::io::DownloadError;
+use crate::matchup::Matchup;
+use crate::player_stats::PlayerStats;
+use crate::replay_response::{Board, MinoType};
+use crate::replay_source::{content_hash, ReplayError};
+use crate::ruleset::Ruleset;
+use crate::solver::{solve_line, solver_pool, PendingGarbage};
+use crate::{
+    collect_replay_id, lookup_cache, process_replay, CacheLookup, CollectedStats, FetchError,
+    ReplaySummary, RunOpts,
+};
+
+///body of `POST /stats`, every field is optional but at least one replay is required
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StatsRequest {
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    replay_ids: Vec<String>,
+    #[serde(default)]
+    replays: Vec<UploadedReplay>,
+    #[serde(default)]
+    rounds: bool, //also report stats per player per game
+    #[serde(default)]
+    matchups: bool, //also report head to head stats of two player replays
+}
+
+///a replay sent inline, either as the ttr/ttrm json itself or as a string of it.
+///the hash is optional and only checked, the server keys the cache by its own
+#[derive(Deserialize)]
+struct UploadedReplay {
+    #[serde(default)]
+    hash: Option<String>,
+    replay: Value,
+}
+
+#[derive(Serialize, Clone)]
+struct ReplayFailure {
+    replay: String,
+    code: &'static str,
+    message: String,
+    #[serde(skip)]
+    status: StatusCode,
+}
+
+///stats of one player in one game, round is the game's index within its replay
+#[derive(Serialize)]
+struct RoundStats {
+    replay: String,
+    round: usize,
+    player: String,
+    stats: PlayerStats,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    players: HashMap<String, PlayerStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rounds: Option<Vec<RoundStats>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matchups: Option<Vec<Matchup>>,
+    failures: Vec<ReplayFailure>,
+}
+
+///body of `POST /solve`, a board in the same form placements report it
+#[derive(Deserialize)]
+struct SolveRequest {
+    board: Board,
+    queue: Vec<MinoType>, //hold first, then the preview
+    #[serde(default)]
+    btb: usize,
+    #[serde(default)]
+    combo: usize,
+    #[serde(default)]
+    garbage: Vec<PendingGarbage>,
+    #[serde(default)]
+    ruleset: Ruleset,
+}
+
+///one line of the `POST /stats/stream` ndjson body, the stats event is always last
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ProgressEvent {
+    #[serde(rename_all = "camelCase")]
+    Replay {
+        replay: String,
+        index: usize,
+        total: usize,
+        #[serde(flatten)]
+        summary: ReplaySummary,
+        elapsed_ms: u128,
+    },
+    #[serde(rename_all = "camelCase")]
+    ReplayFailed {
+        index: usize,
+        total: usize,
+        #[serde(flatten)]
+        failure: ReplayFailure,
+        elapsed_ms: u128,
+    },
+    Stats(StatsResponse),
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    code: &'static str,
+    message: String,
+    failures: Vec<ReplayFailure>,
+}
+
+fn replay_status(e: &ReplayError) -> StatusCode {
+    match e {
+        ReplayError::Unsupported | ReplayError::Corrupt => StatusCode::UNPROCESSABLE_ENTITY,
+        ReplayError::Unparsable | ReplayError::HashMismatch => StatusCode::BAD_REQUEST,
+        ReplayError::Unmunchable => StatusCode::INTERNAL_SERVER_ERROR,
+        ReplayError::Connection => StatusCode::BAD_GATEWAY,
+    }
+}
+
+fn download_status(e: &DownloadError) -> StatusCode {
+    match e {
+        DownloadError::Unsuccessful => StatusCode::NOT_FOUND,
+        DownloadError::Corrupted | DownloadError::Request(_) => StatusCode::BAD_GATEWAY,
+    }
+}
+
+impl ReplayFailure {
+    fn new(replay: &str, e: &FetchError) -> Self {
+        let (code, message, status) = match e {
+            FetchError::Download(e) => (e.code(), e.to_string(), download_status(e)),
+            FetchError::Replay(e) => (e.code(), e.to_string(), replay_status(e)),
+        };
+        Self {
+            replay: replay.to_string(),
+            code,
+            message,
+            status,
+        }
+    }
+}
+
+fn error_response(
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    failures: Vec<ReplayFailure>,
+) -> Response {
+    (
+        status,
+        Json(ErrorResponse {
+            code,
+            message,
+            failures,
+        }),
+    )
+        .into_response()
+}
+
+///bodies over `max_body_bytes` are rejected before any replay in them is read
+pub fn router(opts: Arc<RunOpts>, max_body_bytes: usize) -> Router {
+    Router::new()
+        .route("/stats", post(stats))
+        .route("/stats/stream", post(stats_stream))
+        .route("/solve", post(solve))
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .with_state(opts)
+}
+
+fn rejection_response(rejection: JsonRejection) -> Response {
+    let code = match rejection.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => "body_too_large",
+        _ => "invalid_request",
+    };
+    error_response(rejection.status(), code, rejection.body_text(), Vec::new())
+}
+
+///a request body, or the response to send back if it is unusable
+fn parse_request(
+    request: Result<Json<StatsRequest>, JsonRejection>,
+) -> Result<StatsRequest, Response> {
+    let Json(request) = request.map_err(rejection_response)?;
+    if request.replay_ids.is_empty() && request.replays.is_empty() {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            "no_replays",
+            "The request contained no replay ids or replays.".to_string(),
+            Vec::new(),
+        ));
+    }
+    Ok(request)
+}
+
+///stats of an uploaded replay, keyed in the cache by its content hash
+async fn collect_uploaded_replay(
+    replay: &str,
+    hash: &str,
+    claimed_hash: Option<&str>,
+    filtered: &[String],
+    collected: &mut CollectedStats,
+    opts: &RunOpts,
+) -> Result<ReplaySummary, FetchError> {
+    if claimed_hash.is_some_and(|claimed| !claimed.trim().eq_ignore_ascii_case(hash)) {
+        return Err(FetchError::Replay(ReplayError::HashMismatch));
+    }
+    let cached_stats = match lookup_cache(hash, filtered, collected, opts.cache.as_deref(), false) {
+        CacheLookup::Hit(players) | CacheLookup::Stale(players) => {
+            return Ok(ReplaySummary {
+                cache_hit: true,
+                players,
+                ..Default::default()
+            })
+        }
+        CacheLookup::Miss(cached_stats) => cached_stats,
+    };
+    process_replay(replay, filtered, collected, hash, cached_stats, opts)
+        .await
+        .map_err(FetchError::Replay)
+}
+
+enum Job {
+    Id(String),
+    Upload(UploadedReplay),
+}
+
+///processes every replay of the request in order, reporting each one to progress if given
+async fn process_request(
+    opts: &Arc<RunOpts>,
+    request: StatsRequest,
+    progress: Option<&mpsc::Sender<ProgressEvent>>,
+) -> (CollectedStats, Vec<ReplayFailure>) {
+    let filtered: Vec<String> = request
+        .names
+        .iter()
+        .map(|x| x.to_ascii_lowercase().trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect();
+    //map names to lowercase, so that name searching is case insensitive
+
+    let jobs: Vec<Job> = request
+        .replay_ids
+        .into_iter()
+        .map(|replay_id| Job::Id(replay_id.trim().to_string())) //keyed the same as the line protocol
+        .chain(request.replays.into_iter().map(Job::Upload))
+        .collect();
+    let total = jobs.len();
+
+    let mut collected = CollectedStats::new(request.rounds, request.matchups);
+    let mut failures = Vec::new();
+
+    for (index, job) in jobs.into_iter().enumerate() {
+        let start = Instant::now();
+        let (replay, result) = match job {
+            Job::Id(replay_id) => {
+                let result = collect_replay_id(&replay_id, &filtered, &mut collected, opts).await;
+                (replay_id, result)
+            }
+            Job::Upload(uploaded) => {
+                let replay = match uploaded.replay {
+                    Value::String(replay) => replay,
+                    replay => replay.to_string(),
+                };
+                let hash = content_hash(&replay);
+                let result = collect_uploaded_replay(
+                    &replay,
+                    &hash,
+                    uploaded.hash.as_deref(),
+                    &filtered,
+                    &mut collected,
+                    opts,
+                )
+                .await;
+                (hash, result)
+            }
+        };
+        let elapsed_ms = start.elapsed().as_millis();
+
+        let event = match result {
+            Ok(summary) => ProgressEvent::Replay {
+                replay,
+                index,
+                total,
+                summary,
+                elapsed_ms,
+            },
+            Err(e) => {
+                let failure = ReplayFailure::new(&replay, &e);
+                failures.push(failure.clone());
+                ProgressEvent::ReplayFailed {
+                    index,
+                    total,
+                    failure,
+                    elapsed_ms,
+                }
+            }
+        };
+        if let Some(progress) = progress {
+            let _ = progress.send(event).await; //a client that hung up still gets its replays cached
+        }
+    }
+    (collected, failures)
+}
+
+fn transform_stats(collected: CollectedStats, failures: Vec<ReplayFailure>) -> StatsResponse {
+    let players = collected
+        .players
+        .into_iter()
+        .map(|(username, stats)| (username, PlayerStats::from(&stats)))
+        .collect();
+    let rounds = collected.rounds.map(|rounds| {
+        rounds
+            .into_iter()
+            .map(|round| RoundStats {
+                replay: round.replay,
+                round: round.round,
+                player: round.name,
+                stats: PlayerStats::from(&round.stats),
+            })
+            .collect()
+    });
+    let matchups = collected
+        .matchups
+        .map(|matchups| matchups.into_values().collect());
+    StatsResponse {
+        players,
+        rounds,
+        matchups,
+        failures,
+    }
+}
+
+///stats for the requested players over every replay that could be processed.
+///replays that fail are listed in `failures`, the request only fails if none succeeded
+async fn stats(
+    State(opts): State<Arc<RunOpts>>,
+    request: Result<Json<StatsRequest>, JsonRejection>,
+) -> Response {
+    let request = match parse_request(request) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+
+    let (collected, failures) = process_request(&opts, request, None).await;
+
+    if collected.players.is_empty() {
+        if let Some(first) = failures.first() {
+            let (status, code, message) = (first.status, first.code, first.message.clone());
+            return error_response(status, code, message, failures);
+        }
+        return error_response(
+            StatusCode::NOT_FOUND,
+            "no_players",
+            "None of the requested players were found in the replays.".to_string(),
+            failures,
+        );
+    }
+
+    (StatusCode::OK, Json(transform_stats(collected, failures))).into_response()
+}
+
+///same as `/stats`, but streams an ndjson event per replay as it finishes, followed by the stats
+async fn stats_stream(
+    State(opts): State<Arc<RunOpts>>,
+    request: Result<Json<StatsRequest>, JsonRejection>,
+) -> Response {
+    let request = match parse_request(request) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let (collected, failures) = process_request(&opts, request, Some(&tx)).await;
+        let _ = tx
+            .send(ProgressEvent::Stats(transform_stats(collected, failures)))
+            .await;
+    });
+
+    let lines = ReceiverStream::new(rx).map(|event| {
+        let mut line = serde_json::to_string(&event).unwrap_or_default();
+        line.push('\n');
+        Ok::<_, Infallible>(line)
+    });
+
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+///the strongest line the solver finds on one board, attack and defence as stats count them
+async fn solve(request: Result<Json<SolveRequest>, JsonRejection>) -> Response {
+    let request = match request {
+        Ok(Json(request)) => request,
+        Err(rejection) => return rejection_response(rejection),
+    };
+    if request.board.len() != BOARD_WIDTH * BOARD_HEIGHT
+        || !request.ruleset.fits_board()
+        || request.queue.is_empty()
+        || request.queue.iter().any(|&mino| to_shape(mino).is_none())
+    {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_board",
+            "The board must be 10x40, the ruleset 10 wide and the queue at least one piece."
+                .to_string(),
+            Vec::new(),
+        );
+    }
+
+    let solved = tokio::task::spawn_blocking(move || {
+        solver_pool().install(|| {
+            solve_line(
+                &request.board,
+                request.btb,
+                request.combo,
+                &request.queue,
+                &request.garbage,
+                &request.ruleset,
+            )
+        })
+    })
+    .await;
+    match solved {
+        Ok(line) => (StatusCode::OK, Json(line)).into_response(),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "solver_failed",
+            e.to_string(),
+            Vec::new(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay_source::NativeDecoder;
+
+    ///the api on a free local port, without a cache or archive
+    async fn serve(max_body_bytes: usize) -> std::net::SocketAddr {
+        let opts = Arc::new(RunOpts {
+            cache: None,
+            archive: None,
+            source: Arc::new(NativeDecoder),
+            token: String::new(),
+            refreshing: Default::default(),
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(opts, max_body_bytes)).await });
+        address
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_rejected_with_a_code() {
+        let address = serve(64).await;
+
+        let body = serde_json::json!({ "replays": ["x".repeat(256)] });
+        let response = reqwest::Client::new()
+            .post(format!("http://{address}/stats"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE.as_u16());
+        let error: Value = response.json().await.unwrap();
+        assert_eq!(error["code"], "body_too_large");
+    }
+
+    #[tokio::test]
+    async fn solve_returns_the_line() {
+        let address = serve(1024 * 1024).await;
+        let mut board = vec![MinoType::Empty; 400];
+        for (i, cell) in board.iter_mut().enumerate().skip(350) {
+            let (x, y) = (i % 10, 39 - i / 10); //y up from the floor
+            if (y == 0 && x != 1) || (y > 0 && x != 0) {
+                *cell = MinoType::Garbage;
+            }
+        }
+        let body = serde_json::json!({
+            "board": board,
+            "queue": [MinoType::I, MinoType::I, MinoType::I],
+        });
+        let response = reqwest::Client::new()
+            .post(format!("http://{address}/solve"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK.as_u16());
+        let line: Value = response.json().await.unwrap();
+        assert_eq!(line["attack"], 4);
+        assert_eq!(line["steps"][0]["clearType"], "QUAD");
+        assert_eq!(line["steps"][0]["attack"], 4);
+
+        let empty_queue = serde_json::json!({ "board": board, "queue": [] });
+        let response = reqwest::Client::new()
+            .post(format!("http://{address}/solve"))
+            .json(&empty_queue)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST.as_u16());
+    }
+}
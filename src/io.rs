@@ -35,6 +35,16 @@ pub enum DownloadError {
 
 impl std::error::Error for DownloadError {}
 
+impl DownloadError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            DownloadError::Unsuccessful=>"download_unsuccessful",
+            DownloadError::Corrupted=>"download_corrupted",
+            DownloadError::Request(_)=>"download_failed"
+        }
+    }
+}
+
 impl std::fmt::Display for DownloadError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -1,6 +1,15 @@
 pub mod attack;
 pub mod board_analyzer;
+pub mod engine;
+pub mod files;
+pub mod finesse;
+pub mod matchup;
+pub mod opener;
+pub mod pc_finder;
 pub mod placement_stats;
 pub mod player_stats;
+pub mod replay_decoder;
 pub mod replay_response;
+pub mod replay_source;
+pub mod ruleset;
 pub mod solver;
@@ -1,32 +1,213 @@
-mod attack;
-mod board_analyzer;
 mod cache;
+mod http_api;
 mod io;
-mod placement_stats;
-mod player_stats;
-mod replay_response;
-mod solver;
 
-use cache::{get_cached_stats, initialize_cache, set_cached_stats};
-use io::{download_replay, io_auth};
+use action_parser::{
+    board_analyzer, engine, files, matchup, placement_stats, player_stats, replay_decoder,
+    replay_response, replay_source, ruleset, solver,
+};
+//the library's modules are reused rather than compiled into the bin a second time
+
+use cache::{
+    spawn_janitor, CacheEntry, CacheLimits, CacheStamp, FsCache, LruStatsCache, SqliteCache,
+    StatsCache, CACHE_PATH, SQLITE_PATH,
+};
+use io::{download_replay, io_auth, DownloadError};
+use matchup::{matchup, Matchup};
 use placement_stats::CumulativePlacementStats;
 use player_stats::PlayerStats;
-use replay_response::PlacementStats;
-use std::sync::Arc;
-use std::{collections::HashMap, error::Error};
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpSocket, TcpStream};
-use tokio::{
-    io::{AsyncBufReadExt, BufReader},
-    task::JoinSet,
+use replay_decoder::round_winners;
+use replay_source::{
+    content_hash, is_requested, munch_games, munch_players, sanitize_string, NativeDecoder,
+    ParserClient, PlacementArchive, ReplayError, ReplaySource, ARCHIVE_PATH,
 };
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+///what processing one replay yielded, reported to streaming clients
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReplaySummary {
+    cache_hit: bool,
+    stale: bool, //served from an entry an older version computed, a fresh one is on its way
+    players: Vec<String>,
+    games: Option<usize>, //game counts aren't cached, none on a cache hit
+    corrupt_games: Option<usize>,
+}
+
+///stats of one player in one game of a replay
+struct RoundEntry {
+    replay: String,
+    round: usize,
+    name: String,
+    stats: CumulativePlacementStats,
+}
+
+///stats gathered over a request, merged per player and optionally kept per round
+#[derive(Default)]
+struct CollectedStats {
+    players: HashMap<String, CumulativePlacementStats>,
+    rounds: Option<Vec<RoundEntry>>, //some if the client asked for a per round breakdown
+    matchups: Option<HashMap<[String; 2], Matchup>>, //some if the client asked for head to head stats
+}
+
+impl CollectedStats {
+    fn new(rounds: bool, matchups: bool) -> Self {
+        Self {
+            players: HashMap::new(),
+            rounds: rounds.then(Vec::new),
+            matchups: matchups.then(HashMap::new),
+        }
+    }
+    ///the cache only holds merged stats, anything per game needs the replay processed
+    fn needs_games(&self) -> bool {
+        self.rounds.is_some() || self.matchups.is_some()
+    }
+}
 
-///removes wrapper characters around tcp streams
-fn sanitize_string(s: &str) -> String {
-    s.trim_start_matches('\u{feff}')
-        .trim_end_matches('\n')
-        .trim_end_matches('\r')
-        .to_string()
+///result of asking the cache for a replay's stats
+enum CacheLookup {
+    Hit(Vec<String>),   //names of the players merged from the cache
+    Stale(Vec<String>), //merged all the same, the entry should be recomputed off the request path
+    Miss(Option<HashMap<String, CumulativePlacementStats>>), //entry to complete and save once processed, none if caching is disabled
+}
+
+///merges cached stats into collected if every requested player of the replay is cached.
+///stale entries are only served if `serve_stale`, otherwise they're a miss and get written over
+fn lookup_cache(
+    handle: &str,
+    filtered: &[String],
+    collected: &mut CollectedStats,
+    cache: Option<&dyn StatsCache>,
+    serve_stale: bool,
+) -> CacheLookup {
+    let Some(cache) = cache else {
+        return CacheLookup::Miss(None);
+    };
+    let (stats, stale) = match cache.get(handle) {
+        None => return CacheLookup::Miss(Some(HashMap::new())),
+        Some(entry) if entry.stamp != CacheStamp::current() => {
+            if !serve_stale {
+                return CacheLookup::Miss(Some(HashMap::new()));
+            }
+            (entry.players, true)
+        }
+        Some(entry) => (entry.players, false),
+    };
+    if collected.needs_games()
+        || !((filtered.len() == 0 && stats.len() == 2)
+            || filtered.iter().all(|name| {
+                stats
+                    .keys()
+                    .any(|cached_name| &cached_name.to_lowercase() == name)
+            }))
+    {
+        let kept = if stale { HashMap::new() } else { stats };
+        return CacheLookup::Miss(Some(kept)); //a fresh entry can't be completed from a stale one
+    }
+    let mut names = Vec::new();
+    for (name, cumulative_stats) in stats {
+        if filtered.len() > 0 && !filtered.contains(&name.to_lowercase()) {
+            continue;
+        }
+        names.push(name.clone());
+        match collected.players.entry(name) {
+            std::collections::hash_map::Entry::Occupied(mut entry) => {
+                entry.get_mut().absorb(cumulative_stats);
+            }
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(cumulative_stats);
+            }
+        }
+    }
+    if stale {
+        CacheLookup::Stale(names)
+    } else {
+        CacheLookup::Hit(names)
+    }
+}
+
+///recomputes a replay's stale entry in the background, once at a time per replay
+fn refresh_in_background(replay_id: &str, opts: &Arc<RunOpts>) {
+    let mut refreshing = opts.refreshing.lock().unwrap_or_else(|e| e.into_inner());
+    if !refreshing.insert(replay_id.to_string()) {
+        return;
+    }
+    drop(refreshing);
+    let opts = Arc::clone(opts);
+    let replay_id = replay_id.to_string();
+    tokio::spawn(async move {
+        let mut collected = CollectedStats::default();
+        let refreshed = match download_replay(&replay_id, &opts.token).await {
+            Ok(replay) => process_replay(
+                &replay,
+                &[],
+                &mut collected,
+                &replay_id,
+                Some(HashMap::new()),
+                &opts,
+            )
+            .await
+            .map_err(FetchError::Replay),
+            Err(e) => Err(FetchError::Download(e)),
+        };
+        if let Err(e) = refreshed {
+            eprintln!("unable to refresh stale stats of {}! {:?}", replay_id, e);
+        }
+        opts.refreshing
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&replay_id);
+    });
+}
+
+#[derive(Debug)]
+enum FetchError {
+    Download(DownloadError),
+    Replay(ReplayError),
+}
+
+///stats of a tetrio replay by id, downloaded unless cached. a stale entry is served as is
+///while the replay is downloaded again in the background
+async fn collect_replay_id(
+    replay_id: &str,
+    filtered: &[String],
+    collected: &mut CollectedStats,
+    opts: &Arc<RunOpts>,
+) -> Result<ReplaySummary, FetchError> {
+    let lookup = lookup_cache(replay_id, filtered, collected, opts.cache.as_deref(), true);
+    let cached_stats = match lookup {
+        CacheLookup::Hit(players) => {
+            return Ok(ReplaySummary {
+                cache_hit: true,
+                players,
+                ..Default::default()
+            })
+        }
+        CacheLookup::Stale(players) => {
+            refresh_in_background(replay_id, opts);
+            return Ok(ReplaySummary {
+                cache_hit: true,
+                stale: true,
+                players,
+                ..Default::default()
+            });
+        }
+        CacheLookup::Miss(cached_stats) => cached_stats,
+    };
+
+    let replay = download_replay(replay_id, &opts.token)
+        .await
+        .map_err(FetchError::Download)?;
+
+    process_replay(&replay, filtered, collected, replay_id, cached_stats, opts)
+        .await
+        .map_err(FetchError::Replay)
 }
 
 ///handling the client
@@ -46,7 +227,7 @@ async fn handle_client(
         .collect();
     //map names to lowercase, so that name searching is case insensitive
 
-    let mut player_stats: HashMap<String, CumulativePlacementStats> = HashMap::new();
+    let mut collected = CollectedStats::default();
     //we keep a map of players' cumulative placemenet stats, then transform to advanced stats after
 
     let mut num_replay_ids = String::new();
@@ -57,67 +238,16 @@ async fn handle_client(
     for _ in 0..num_replay_ids {
         let mut replay_id = String::new();
         stream.read_line(&mut replay_id).await?;
+        let replay_id = sanitize_string(&replay_id).trim().to_string();
+        //the one key the replay is downloaded, cached and archived under
 
-        let cached_stats = if opts.caching_enabled {
-            let cached_stats = get_cached_stats(&replay_id);
-            let cached_stats = match cached_stats {
-                None => Some(HashMap::new()),
-                Some(stats) => {
-                    if (filtered_names.len() == 0 && stats.len() == 2)
-                        || filtered_names.iter().all(|name| {
-                            stats
-                                .keys()
-                                .any(|cached_name| &cached_name.to_lowercase() == name)
-                        })
-                    {
-                        for (name, cumulative_stats) in stats {
-                            if filtered_names.len() > 0
-                                && !filtered_names.contains(&name.to_lowercase())
-                            {
-                                continue;
-                            }
-                            match player_stats.entry(name) {
-                                std::collections::hash_map::Entry::Occupied(mut entry) => {
-                                    entry.get_mut().absorb(cumulative_stats);
-                                }
-                                std::collections::hash_map::Entry::Vacant(entry) => {
-                                    entry.insert(cumulative_stats);
-                                }
-                            }
-                        }
-                        write_line(&mut stream, "success").await?;
-                        continue;
-                    }
-                    Some(stats)
-                }
-            };
-            cached_stats
-        } else {
-            None
-        };
-
-        let replay = download_replay(&replay_id, &opts.token).await;
-        let replay = match replay {
-            Ok(replay) => replay,
-            Err(e) => {
+        match collect_replay_id(&replay_id, &filtered_names, &mut collected, &opts).await {
+            Ok(_) => write_line(&mut stream, "success").await?,
+            Err(FetchError::Download(e)) => {
                 eprintln!("ERROR DOWNLOADING REPLAY: {}", e);
                 write_line(&mut stream, "error downloading replay").await?;
-                continue;
             }
-        };
-
-        if let Err(e) = process_replay(
-            &replay,
-            &filtered_names,
-            &mut player_stats,
-            &replay_id,
-            cached_stats,
-        )
-        .await
-        {
-            write_line(&mut stream, &format!("{e}")).await?;
-        } else {
-            write_line(&mut stream, "success").await?;
+            Err(FetchError::Replay(e)) => write_line(&mut stream, &format!("{e}")).await?,
         }
     }
 
@@ -129,56 +259,41 @@ async fn handle_client(
     for _ in 0..num_replays {
         let mut hash = String::new();
         stream.read_line(&mut hash).await?;
+        let hash = sanitize_string(&hash);
+        //entries are only ever written under hashes the server computed, so looking up a claimed one is safe
 
-        let cached_stats = if opts.caching_enabled {
-            let cached_stats = get_cached_stats(&hash);
-            let cached_stats = match cached_stats {
-                None => Some(HashMap::new()),
-                Some(stats) => {
-                    if (filtered_names.len() == 0 && stats.len() == 2)
-                        || filtered_names.iter().all(|name| {
-                            stats
-                                .keys()
-                                .any(|cached_name| &cached_name.to_lowercase() == name)
-                        })
-                    {
-                        for (name, cumulative_stats) in stats {
-                            if filtered_names.len() > 0
-                                && !filtered_names.contains(&name.to_lowercase())
-                            {
-                                continue;
-                            }
-                            match player_stats.entry(name) {
-                                std::collections::hash_map::Entry::Occupied(mut entry) => {
-                                    entry.get_mut().absorb(cumulative_stats);
-                                }
-                                std::collections::hash_map::Entry::Vacant(entry) => {
-                                    entry.insert(cumulative_stats);
-                                }
-                            }
-                        }
-                        write_line(&mut stream, "true").await?;
-                        continue;
-                    }
-                    Some(stats)
-                }
-            };
-            write_line(&mut stream, "false").await?;
-            cached_stats
-        } else {
-            write_line(&mut stream, "false").await?;
-            None
+        let cached_stats = match lookup_cache(
+            &hash,
+            &filtered_names,
+            &mut collected,
+            opts.cache.as_deref(),
+            false,
+        ) {
+            CacheLookup::Hit(_) | CacheLookup::Stale(_) => {
+                write_line(&mut stream, "true").await?;
+                continue;
+            }
+            CacheLookup::Miss(cached_stats) => {
+                write_line(&mut stream, "false").await?;
+                cached_stats
+            }
         };
 
         let mut replay = String::new();
         stream.read_line(&mut replay).await?;
 
+        if !content_hash(&replay).eq_ignore_ascii_case(hash.trim()) {
+            write_line(&mut stream, &format!("{}", ReplayError::HashMismatch)).await?;
+            continue;
+        }
+
         if let Err(e) = process_replay(
             &replay,
             &filtered_names,
-            &mut player_stats,
+            &mut collected,
             &hash,
             cached_stats,
+            &opts,
         )
         .await
         {
@@ -188,7 +303,8 @@ async fn handle_client(
         }
     }
 
-    let player_stats: HashMap<_, _> = player_stats
+    let player_stats: HashMap<_, _> = collected
+        .players
         .into_iter()
         .map(|(username, stats)| (username, PlayerStats::from(&stats)))
         .collect();
@@ -201,40 +317,6 @@ async fn handle_client(
     Ok(())
 }
 
-#[derive(Debug)]
-enum ReplayError {
-    Unsupported,
-    Unparsable,
-    Unmunchable,
-    Corrupt,
-    Connection,
-}
-
-impl Error for ReplayError {}
-
-impl std::fmt::Display for ReplayError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            ReplayError::Unsupported => write!(f, "The replay's version is unsupported."),
-            ReplayError::Unparsable => write!(
-                f,
-                "The replay was unable to be identified as a valid replay."
-            ),
-            ReplayError::Unmunchable => write!(
-                f,
-                "The replay's data was unable to be processed into stats."
-            ),
-            ReplayError::Corrupt => write!(
-                f,
-                "The replay is corrupt, no data was able to be processed."
-            ),
-            ReplayError::Connection => {
-                write!(f, "A connection error occurred with the replay parser.")
-            }
-        }
-    }
-}
-
 async fn write_line(stream: &mut BufReader<TcpStream>, line: &str) -> Result<(), ReplayError> {
     let line_bytes = line.as_bytes();
     stream
@@ -252,107 +334,82 @@ async fn write_line(stream: &mut BufReader<TcpStream>, line: &str) -> Result<(),
 async fn process_replay(
     replay: &str,
     filtered: &[String],
-    player_stats: &mut HashMap<String, CumulativePlacementStats>,
+    collected: &mut CollectedStats,
     cached_handle: &str,
     mut cached_stats: Option<HashMap<String, CumulativePlacementStats>>, //mutable cache to save later
-) -> Result<(), ReplayError> {
+    opts: &RunOpts,
+) -> Result<ReplaySummary, ReplayError> {
+    let source = Arc::clone(&opts.source);
     let mut cached_stats_updated = false;
 
-    let addr: usize = std::env::var("TETRIO_PARSER_PORT")
-        .ok()
-        .and_then(|s: String| s.parse().ok())
-        .unwrap_or(8080);
-    //address of the modded csdotnet replay parser tcp connection, default 8080
-
-    let addr = format!("127.0.0.1:{}", addr)
-        .parse()
-        .or(Err(ReplayError::Connection))?;
-
-    let socket = TcpSocket::new_v4().or(Err(ReplayError::Connection))?;
-    let stream = socket
-        .connect(addr)
-        .await
-        .or(Err(ReplayError::Connection))?;
-    let mut stream = BufReader::new(stream);
-
-    write_line(&mut stream, &sanitize_string(replay)).await?;
-    //write replay string
-
-    let mut supported = String::new();
-    stream
-        .read_line(&mut supported)
-        .await
-        .or(Err(ReplayError::Unparsable))?;
-    let supported: bool = sanitize_string(&supported)
-        .parse()
-        .or(Err(ReplayError::Unparsable))?;
-
-    if !supported {
-        return Err(ReplayError::Unsupported);
-    }
-    //ask parser if version is supported or not
-
-    let mut names = String::new();
-    stream
-        .read_line(&mut names)
-        .await
-        .or(Err(ReplayError::Unparsable))?;
-    let names: Vec<_> = sanitize_string(&names)
-        .split(' ')
-        .map(|s| s.to_string())
-        .collect();
-    //get names in replay
-
-    let names = if filtered.is_empty() {
-        names
-    } else {
-        names
-            .into_iter()
-            .filter(|x| filtered.contains(&x.to_lowercase()))
-            .collect()
+    let replay = sanitize_string(replay);
+    let filtered = filtered.to_vec();
+    let handle = cached_handle.to_string();
+    let wants_matchup = collected.matchups.is_some();
+    let wants_everyone = wants_matchup || opts.archive.is_some();
+    let archive = opts.archive.clone();
+    let (players, games, corrupt_games, replay_matchup) = tokio::task::spawn_blocking(move || {
+        let mut players = if wants_everyone {
+            source.placements(&replay, &[])?
+        } else {
+            source.placements(&replay, &filtered)?
+        };
+        if let Some(archive) = archive {
+            if let Err(e) = archive.store(&handle, &players) {
+                eprintln!("unable to archive placements of {}! {}", handle, e);
+            }
+        }
+        let winners = if wants_matchup {
+            round_winners(&replay)
+        } else {
+            Vec::new()
+        };
+        let replay_matchup = matchup(&handle, &players, &winners).filter(|matchup| {
+            matchup
+                .players
+                .iter()
+                .any(|name| is_requested(name, &filtered))
+        });
+        players.retain(|(name, _)| is_requested(name, &filtered));
+        //a matchup needs both players, so they are only filtered after it is built
+
+        let games = players.iter().map(|(_, games)| games.len()).sum::<usize>();
+        let corrupt_games = players
+            .iter()
+            .flat_map(|(_, games)| games.iter())
+            .filter(|game| game.is_none())
+            .count();
+        Ok::<_, ReplayError>((munch_games(players)?, games, corrupt_games, replay_matchup))
+    })
+    .await
+    .or(Err(ReplayError::Unmunchable))??;
+    //pulling placements and transforming them into stats is heavy, keep it off the async workers
+
+    let mut summary = ReplaySummary {
+        cache_hit: false,
+        stale: false,
+        players: Vec::new(),
+        games: Some(games),
+        corrupt_games: Some(corrupt_games),
     };
-    //if filtered name list is empty, don't modify. else filter with case insensitivity
-
-    let mut num_games = String::new();
-    stream
-        .read_line(&mut num_games)
-        .await
-        .or(Err(ReplayError::Unparsable))?;
-    let num_games: usize = sanitize_string(&num_games)
-        .parse()
-        .or(Err(ReplayError::Unparsable))?;
-    //get number of games of replay
-
-    write_line(&mut stream, &names.len().to_string()).await?;
-    //write number of names to get stats for
 
-    let mut fully_corrupt = true;
+    for (name, games) in players {
+        summary.players.push(name.clone());
 
-    for name in names {
         let mut cumulative_stats = CumulativePlacementStats::default();
-        let mut handles = JoinSet::new();
-        //joinset to process stat transformation multithreadedly
-
-        write_line(&mut stream, &name).await?;
-
-        for _ in 0..num_games {
-            let mut game = String::new();
-            stream
-                .read_line(&mut game)
-                .await
-                .or(Err(ReplayError::Unparsable))?; //parse individual placement sequences for each game
-            if sanitize_string(&game) == "CORRUPT" {
-                continue;
+        for (round, game_stats) in games {
+            match collected.rounds.as_mut() {
+                Some(rounds) => {
+                    cumulative_stats.absorb_ref(&game_stats);
+                    rounds.push(RoundEntry {
+                        replay: cached_handle.to_string(),
+                        round,
+                        name: name.clone(),
+                        stats: game_stats,
+                    });
+                }
+                None => cumulative_stats.absorb(game_stats),
             }
-            fully_corrupt = false;
-            let placements: Vec<PlacementStats> =
-                serde_json::from_str(&game).or(Err(ReplayError::Unmunchable))?; //something went wrong in the response loop, error should never happen
-            handles.spawn_blocking(move || CumulativePlacementStats::from(placements.as_slice()));
-            //create handle to parse stats, this from operation is heavy
-        }
-        while let Some(handle) = handles.join_next().await {
-            let game_stats = handle.or(Err(ReplayError::Unmunchable))?;
-            cumulative_stats.absorb(game_stats); //join all handles and their respective stats
         }
 
         if let Some(mut stats) = cached_stats {
@@ -363,7 +420,7 @@ async fn process_replay(
             cached_stats = Some(stats);
         }
 
-        match player_stats.entry(name) {
+        match collected.players.entry(name) {
             std::collections::hash_map::Entry::Occupied(mut entry) => {
                 entry.get_mut().absorb(cumulative_stats);
             }
@@ -373,22 +430,62 @@ async fn process_replay(
         }
         //merge stats for respective player
     }
-    if let Some(cached_stats) = cached_stats {
+    if let (Some(cache), Some(cached_stats)) = (opts.cache.as_deref(), cached_stats) {
         if cached_stats_updated {
-            set_cached_stats(cached_handle, &cached_stats);
+            let entry = CacheEntry {
+                stamp: CacheStamp::current(),
+                players: cached_stats,
+            };
+            cache.set(cached_handle, &entry);
         }
     }
 
-    if fully_corrupt {
-        return Err(ReplayError::Corrupt);
-    } //no data received? return corrupt
+    if let (Some(matchups), Some(replay_matchup)) = (collected.matchups.as_mut(), replay_matchup) {
+        match matchups.entry(replay_matchup.players.clone()) {
+            std::collections::hash_map::Entry::Occupied(mut entry) => {
+                entry.get_mut().absorb(replay_matchup);
+            }
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(replay_matchup);
+            }
+        }
+    }
 
-    Ok(())
+    Ok(summary)
+}
+
+///rebuilds the cache entry of every archived replay with the current stats, returning how many were
+fn recompute(cache: &dyn StatsCache, archive: &PlacementArchive) -> usize {
+    let handles = match archive.handles() {
+        Ok(handles) => handles,
+        Err(e) => {
+            eprintln!("unable to read placement archive! {}", e);
+            return 0;
+        }
+    };
+    let mut recomputed = 0;
+    for handle in handles {
+        match archive.placements(&handle, &[]).and_then(munch_players) {
+            Ok(players) => {
+                let entry = CacheEntry {
+                    stamp: CacheStamp::current(),
+                    players: players.into_iter().collect(),
+                };
+                cache.set(&handle, &entry);
+                recomputed += 1;
+            }
+            Err(e) => eprintln!("unable to recompute {}! {}", handle, e),
+        }
+    }
+    recomputed
 }
 
 struct RunOpts {
-    caching_enabled: bool,
+    cache: Option<Arc<dyn StatsCache>>, //none if caching is disabled
+    archive: Option<PlacementArchive>,  //none if archiving is disabled
+    source: Arc<dyn ReplaySource>,
     token: String,
+    refreshing: Mutex<HashSet<String>>, //replay ids whose stale entries are being recomputed
 }
 
 #[tokio::main]
@@ -397,13 +494,106 @@ async fn main() {
         .ok()
         .and_then(|s: String| s.parse().ok())
         .unwrap_or(true);
-    initialize_cache();
+    let cache_backend = std::env::var("CACHE_BACKEND").unwrap_or("fs".to_string());
+    let cache_limits = CacheLimits::from_env();
+    let cache: Option<Arc<dyn StatsCache>> = match cache_backend.as_str() {
+        _ if !caching_enabled => None,
+        "sqlite" => match SqliteCache::open(SQLITE_PATH) {
+            Ok(cache) => Some(Arc::new(cache)),
+            Err(e) => {
+                eprintln!(
+                    "unable to open replay cache database, caching disabled! {}",
+                    e
+                );
+                None
+            }
+        },
+        "memory" => Some(Arc::new(LruStatsCache::new(cache_limits.max_entries))),
+        _ => Some(Arc::new(FsCache::open(CACHE_PATH))),
+    };
+    if let Some(cache) = &cache {
+        let usage = cache.usage();
+        println!(
+            "{} cache holds {} replays, {} bytes",
+            cache_backend, usage.entries, usage.bytes
+        );
+    }
+    //files in .replayCache by default, CACHE_BACKEND picks sqlite or memory instead.
+    //CACHE_TTL_SECS, CACHE_MAX_ENTRIES and CACHE_MAX_BYTES bound it, default 5 hours, 1000 replays, 512MiB
+
+    let external_parser: bool = std::env::var("USE_EXTERNAL_PARSER")
+        .ok()
+        .and_then(|s: String| s.parse().ok())
+        .unwrap_or(false);
+    let source: Arc<dyn ReplaySource> = if external_parser {
+        Arc::new(ParserClient::from_env())
+    } else {
+        Arc::new(NativeDecoder)
+    };
+    //replays are decoded in process, USE_EXTERNAL_PARSER=true sends them to the csdotnet parser instead
+
+    let solver_threads: usize = std::env::var("SOLVER_THREADS")
+        .ok()
+        .and_then(|s: String| s.parse().ok())
+        .unwrap_or(0);
+    if let Err(e) = solver::init_solver_pool(solver_threads) {
+        eprintln!("unable to cap solver threads! {}", e);
+    }
+    //cap on threads solving boards across every request, default one per core
+
+    let archiving_enabled: bool = std::env::var("ARCHIVE_PLACEMENTS")
+        .ok()
+        .and_then(|s: String| s.parse().ok())
+        .unwrap_or(false);
+    let archive = match archiving_enabled.then(|| PlacementArchive::open(ARCHIVE_PATH)) {
+        Some(Ok(archive)) => Some(archive),
+        Some(Err(e)) => {
+            eprintln!(
+                "unable to open placement archive, archiving disabled! {}",
+                e
+            );
+            None
+        }
+        None => None,
+    };
+    //raw placements are only kept if asked for, they're far bigger than the stats
+
+    let command = std::env::args().nth(1);
+    if let Some(command) = command
+        .as_deref()
+        .filter(|c| ["stale", "recompute"].contains(c))
+    {
+        match (command, &cache) {
+            (_, None) => println!("caching is disabled"),
+            ("stale", Some(cache)) => println!(
+                "{} of {} cached replays are stale",
+                cache.count_stale(&CacheStamp::current()),
+                cache.usage().entries
+            ),
+            (_, Some(cache)) => match PlacementArchive::open(ARCHIVE_PATH) {
+                Ok(archive) => {
+                    println!("recomputed {} replays", recompute(cache.as_ref(), &archive))
+                }
+                Err(e) => eprintln!("unable to open placement archive! {}", e),
+            },
+        }
+        return;
+    }
+    //`stale` counts entries that would be recomputed, `recompute` rebuilds every archived replay's entry
+
+    if let Some(cache) = &cache {
+        spawn_janitor(Arc::clone(cache), cache_limits);
+    }
+    //evicts once at startup and then every minute
 
     let token = io_auth().await;
 
     let opts = RunOpts {
         token,
-        caching_enabled,
+        cache,
+        archive,
+        source,
+        refreshing: Mutex::new(HashSet::new()),
     };
 
     let shared_opts = Arc::new(opts);
@@ -414,6 +604,28 @@ async fn main() {
         .unwrap_or(8081);
     //listen on port, default 8081
 
+    let http_port: usize = std::env::var("ACTION_PARSER_HTTP_PORT")
+        .ok()
+        .and_then(|s: String| s.parse().ok())
+        .unwrap_or(8082);
+    //http api listens alongside the line protocol, default 8082
+
+    let http_port = format!("127.0.0.1:{}", http_port);
+    println!("http api listening on {}", http_port);
+    let http_listener = TcpListener::bind(http_port).await.unwrap();
+    let max_body_bytes: usize = std::env::var("HTTP_MAX_BODY_BYTES")
+        .ok()
+        .and_then(|s: String| s.parse().ok())
+        .unwrap_or(32 * 1024 * 1024);
+    //largest http request body accepted, default 32MiB so a few uploaded league replays fit
+
+    let router = http_api::router(Arc::clone(&shared_opts), max_body_bytes);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(http_listener, router).await {
+            eprintln!("http api stopped! {}", e);
+        }
+    });
+
     let port = format!("127.0.0.1:{}", port);
     println!("action parser listening on {}", port);
     let listener = TcpListener::bind(port).await.unwrap();
@@ -431,3 +643,35 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stale_cache() -> LruStatsCache {
+        let cache = LruStatsCache::new(10);
+        let players = HashMap::from([("Muncher".to_string(), CumulativePlacementStats::default())]);
+        let stamp = CacheStamp {
+            version: 0,
+            ruleset: String::new(),
+        };
+        cache.set("replay", &CacheEntry { stamp, players });
+        cache
+    }
+
+    #[test]
+    fn stale_entries_are_served_only_when_asked() {
+        let cache = stale_cache();
+        let filtered = ["muncher".to_string()];
+
+        let mut collected = CollectedStats::default();
+        let lookup = lookup_cache("replay", &filtered, &mut collected, Some(&cache), true);
+        assert!(matches!(lookup, CacheLookup::Stale(names) if names == ["Muncher"]));
+        assert!(collected.players.contains_key("Muncher"));
+
+        let mut collected = CollectedStats::default();
+        let lookup = lookup_cache("replay", &filtered, &mut collected, Some(&cache), false);
+        assert!(matches!(lookup, CacheLookup::Miss(Some(stats)) if stats.is_empty()));
+        assert!(collected.players.is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+use serde::Serialize;
+
+use crate::placement_stats::garbage_queue;
+use crate::replay_response::PlacementStats;
+use crate::replay_source::PlayerGames;
+
+///one player's side of a round against their opponent
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchupSide {
+    pub attack_sent: usize,
+    pub attack_received: usize,
+    pub garbage_cancelled: usize,
+    pub garbage_tanked: usize,
+    pub response_frames: f64, //average frames between garbage arriving and the next attack sent
+    pub won: Option<bool>,
+}
+
+impl MatchupSide {
+    fn from(game: &[PlacementStats]) -> Self {
+        let attack_sent = game.iter().map(|p| p.attack.iter().sum::<usize>()).sum();
+        let attack_received = game
+            .iter()
+            .map(|p| p.attack_received.iter().sum::<usize>())
+            .sum();
+        let garbage_cancelled = garbage_queue(game)
+            .iter()
+            .map(|(cancelled, _)| cancelled)
+            .sum();
+        let garbage_tanked = game
+            .iter()
+            .map(|p| p.attack_tanked.iter().sum::<usize>())
+            .sum();
+
+        let mut responses = Vec::new();
+        let mut pressured_for = None;
+        for placement in game {
+            if let Some(frames) = pressured_for.as_mut() {
+                *frames += placement.frame_delay;
+            }
+            if placement.attack.iter().sum::<usize>() > 0 {
+                if let Some(frames) = pressured_for.take() {
+                    responses.push(frames);
+                }
+            }
+            if !placement.attack_received.is_empty() && pressured_for.is_none() {
+                pressured_for = Some(0.0);
+            }
+        }
+        //pressure starts the placement garbage shows up and ends with the next attack
+
+        Self {
+            attack_sent,
+            attack_received,
+            garbage_cancelled,
+            garbage_tanked,
+            response_frames: if responses.is_empty() {
+                0.0
+            } else {
+                responses.iter().sum::<f64>() / responses.len() as f64
+            },
+            won: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MatchupRound {
+    pub replay: String,
+    pub round: usize,
+    pub sides: [MatchupSide; 2],
+}
+
+///head to head record of two players, sides are in the same order as players
+#[derive(Debug, Clone, Serialize)]
+pub struct Matchup {
+    pub players: [String; 2],
+    pub wins: [usize; 2],
+    pub rounds: Vec<MatchupRound>,
+}
+
+impl Matchup {
+    ///combine rounds of the same pairing while consuming the other
+    pub fn absorb(&mut self, other: Matchup) {
+        self.wins[0] += other.wins[0];
+        self.wins[1] += other.wins[1];
+        self.rounds.extend(other.rounds);
+    }
+}
+
+///matchup between the two players of a replay, none unless exactly two players are present.
+///`winners` is the winner's name per round as the replay records it, rounds without one aren't scored
+pub fn matchup(replay: &str, players: &PlayerGames, winners: &[Option<String>]) -> Option<Matchup> {
+    let [(a_name, a_games), (b_name, b_games)] = players.as_slice() else {
+        return None;
+    };
+    let mut sides = [(a_name, a_games), (b_name, b_games)];
+    sides.sort_by_key(|(name, _)| name.to_lowercase());
+    //sorted so the same pair always lands on the same key
+    let [(a_name, a_games), (b_name, b_games)] = sides;
+
+    let mut matchup = Matchup {
+        players: [a_name.clone(), b_name.clone()],
+        wins: [0, 0],
+        rounds: Vec::new(),
+    };
+
+    for (round, (a, b)) in a_games.iter().zip(b_games.iter()).enumerate() {
+        let (Some(a), Some(b)) = (a, b) else {
+            continue;
+        };
+        let mut sides = [MatchupSide::from(a), MatchupSide::from(b)];
+        let winner = winners.get(round).and_then(|winner| {
+            matchup
+                .players
+                .iter()
+                .position(|name| Some(name) == winner.as_ref())
+        });
+        if let Some(winner) = winner {
+            matchup.wins[winner] += 1;
+            sides[winner].won = Some(true);
+            sides[1 - winner].won = Some(false);
+        }
+        matchup.rounds.push(MatchupRound {
+            replay: replay.to_string(),
+            round,
+            sides,
+        });
+    }
+    Some(matchup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board_analyzer::board_from_rows;
+    use crate::replay_response::placement_on;
+
+    fn placement(attack: usize, received: usize, tanked: usize) -> PlacementStats {
+        let amounts = |amount: usize| if amount > 0 { vec![amount] } else { Vec::new() };
+        PlacementStats {
+            attack: vec![attack],
+            attack_received: amounts(received),
+            attack_tanked: amounts(tanked),
+            ..placement_on(board_from_rows(&[]))
+        }
+    }
+
+    fn players() -> PlayerGames {
+        vec![
+            (
+                "b".to_string(),
+                vec![Some(vec![placement(4, 0, 0), placement(0, 0, 0)])],
+            ),
+            (
+                "a".to_string(),
+                vec![Some(vec![placement(0, 4, 0), placement(2, 0, 0)])],
+            ),
+        ]
+    }
+
+    #[test]
+    fn pending_garbage_is_not_cancelled() {
+        let recorded = matchup("replay", &players(), &[]).unwrap();
+        let a = &recorded.rounds[0].sides[0];
+        assert_eq!(a.attack_received, 4);
+        assert_eq!(a.garbage_cancelled, 2);
+        assert_eq!(a.garbage_tanked, 0);
+    }
+
+    #[test]
+    fn response_frames_count_only_answered_garbage() {
+        let recorded = matchup("replay", &players(), &[]).unwrap();
+        let [a, b] = &recorded.rounds[0].sides;
+        assert_eq!(a.response_frames, 10.0);
+        assert_eq!(b.response_frames, 0.0); //never pressured
+    }
+
+    #[test]
+    fn rounds_go_to_the_recorded_winner() {
+        let winners = [Some("b".to_string())];
+        let recorded = matchup("replay", &players(), &winners).unwrap();
+        assert_eq!(recorded.players, ["a".to_string(), "b".to_string()]);
+        assert_eq!(recorded.wins, [0, 1]);
+        assert_eq!(recorded.rounds[0].sides[0].won, Some(false));
+        assert_eq!(recorded.rounds[0].sides[1].won, Some(true));
+
+        let unsettled = matchup("replay", &players(), &[None]).unwrap();
+        assert_eq!(unsettled.wins, [0, 0]);
+        assert_eq!(unsettled.rounds[0].sides[0].won, None);
+    }
+}
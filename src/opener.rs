@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+
+use crate::board_analyzer::{get_garbage_height, get_height};
+use crate::replay_response::{Board, ClearType, MinoType, PlacementStats};
+
+///placements looked at when identifying an opener, two bags
+pub const OPENER_BOARDS: usize = 14;
+
+#[derive(Hash, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[repr(u8)]
+pub enum Opener {
+    Tki,
+    DtCannon,
+    Pco,
+    Mko,
+    CSpin,
+    Freestyle,
+}
+
+impl Opener {
+    pub const ALL: [Opener; 6] = [
+        Opener::Tki,
+        Opener::DtCannon,
+        Opener::Pco,
+        Opener::Mko,
+        Opener::CSpin,
+        Opener::Freestyle,
+    ];
+
+    ///whether a placement cashes in the setup the opener builds
+    pub fn is_payoff(&self, placement: &PlacementStats) -> bool {
+        match self {
+            Opener::Tki | Opener::Mko => placement.clear_type == ClearType::TspinDouble,
+            Opener::DtCannon | Opener::CSpin => placement.clear_type == ClearType::TspinTriple,
+            Opener::Pco => placement.lines_cleared > 0 && get_height(&placement.board) == 0,
+            Opener::Freestyle => placement.clear_type.is_btb_clear(),
+        }
+    }
+}
+
+///rows of a board shape from the bottom up, `#` filled, `.` empty and `?` either.
+///templates are written for the left side and also matched mirrored
+struct Template {
+    opener: Opener,
+    rows: &'static [&'static str],
+}
+
+///most specific shapes first, a tst setup also contains a tsd slot
+const TEMPLATES: [Template; 5] = [
+    Template {
+        opener: Opener::DtCannon,
+        rows: &[
+            "??.#######",
+            "??..######",
+            "??.???????",
+            "?#...?????",
+            "#..???????",
+        ],
+    },
+    Template {
+        opener: Opener::CSpin,
+        rows: &[".#########", "..########", ".?????????", "#?????????"],
+    },
+    Template {
+        opener: Opener::Pco,
+        rows: &[
+            "####??????",
+            "####??????",
+            "####??????",
+            "####??????",
+            "..........",
+        ],
+    },
+    Template {
+        opener: Opener::Tki,
+        rows: &["#.########", "...#######", "?.#???????"],
+    },
+    Template {
+        opener: Opener::Mko,
+        rows: &["##.#######", "#...######", "?#.???????"],
+    },
+];
+
+fn is_filled(board: &Board, x: usize, y: usize) -> bool {
+    board[x + (39 - y) * 10] != MinoType::Empty
+}
+
+impl Template {
+    fn matches(&self, board: &Board, mirrored: bool) -> bool {
+        self.rows.iter().enumerate().all(|(y, row)| {
+            row.bytes().enumerate().all(|(x, cell)| {
+                let x = if mirrored { 9 - x } else { x };
+                match cell {
+                    b'#' => is_filled(board, x, y),
+                    b'.' => !is_filled(board, x, y),
+                    _ => true,
+                }
+            })
+        })
+    }
+}
+
+///the opener built in the first placements of a game, freestyle if no template matches.
+///boards with garbage on them are skipped, the templates only describe clean stacks
+pub fn classify_opener(placements: &[PlacementStats]) -> Opener {
+    placements
+        .iter()
+        .take(OPENER_BOARDS)
+        .filter(|placement| get_garbage_height(&placement.board) == 0)
+        .find_map(|placement| {
+            TEMPLATES
+                .iter()
+                .find(|template| {
+                    template.matches(&placement.board, false)
+                        || template.matches(&placement.board, true)
+                })
+                .map(|template| template.opener)
+        })
+        .unwrap_or(Opener::Freestyle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board_analyzer::board_from_rows;
+    use crate::replay_response::placement_on;
+
+    ///placements leaving each board in turn
+    fn game(boards: &[&[&str]]) -> Vec<PlacementStats> {
+        boards
+            .iter()
+            .map(|rows| placement_on(board_from_rows(rows)))
+            .collect()
+    }
+
+    const TKI: &[&str] = &["..ZZ......", "...ZZSSOOJ", "L.IIIIOOJJ"];
+    const MKO: &[&str] = &[".J........", "J...SSZZOO", "LL.IIIIOOZ"];
+    const DT_CANNON: &[&str] = &[
+        "L.........",
+        "LJ...ZZ...",
+        "IJ.OOZZSST",
+        "IJ..SSLLLT",
+        "IO.IIIIOOJ",
+    ];
+    const PCO: &[&str] = &["LLSS......", "LZZSS..OOI", "LTZZ...OOI", "TTTJJJ...I"];
+    const C_SPIN: &[&str] = &["L.........", ".IIIIOOJJJ", "..LSSOOJZZ", ".LLLSSTTTZ"];
+
+    fn mirrored(rows: &[&str]) -> Vec<String> {
+        rows.iter().map(|row| row.chars().rev().collect()).collect()
+    }
+
+    #[test]
+    fn classifies_each_opener_built_over_a_bag() {
+        let cases: [(&[&str], Opener); 5] = [
+            (TKI, Opener::Tki),
+            (MKO, Opener::Mko),
+            (DT_CANNON, Opener::DtCannon),
+            (PCO, Opener::Pco),
+            (C_SPIN, Opener::CSpin),
+        ];
+        for (setup, opener) in cases {
+            let bottom: &[&str] = &setup[setup.len() - 1..];
+            let placements = game(&[&[], bottom, &setup[1..], setup]);
+            assert_eq!(classify_opener(&placements), opener, "{setup:?}");
+
+            let mirror = mirrored(setup);
+            let mirror: Vec<&str> = mirror.iter().map(String::as_str).collect();
+            assert_eq!(classify_opener(&game(&[&mirror])), opener, "{mirror:?}");
+        }
+    }
+
+    #[test]
+    fn partial_stacks_are_freestyle() {
+        let placements = game(&[&[], &TKI[2..], &MKO[2..], &PCO[3..], &DT_CANNON[3..]]);
+        assert_eq!(classify_opener(&placements), Opener::Freestyle);
+    }
+
+    #[test]
+    fn setups_on_garbage_or_after_two_bags_are_freestyle() {
+        let mut on_garbage: Vec<&str> = TKI.to_vec();
+        on_garbage[2] = "G.GGGGGGGG"; //the same shape, but part of it is garbage
+        assert_eq!(classify_opener(&game(&[&on_garbage])), Opener::Freestyle);
+
+        let mut late: Vec<&[&str]> = vec![&[]; OPENER_BOARDS];
+        late.push(TKI);
+        assert_eq!(classify_opener(&game(&late)), Opener::Freestyle);
+    }
+}
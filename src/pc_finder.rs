@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+
+use bitris::prelude::*;
+
+use crate::board_analyzer::{get_height, to_board64, to_shape};
+use crate::replay_response::{Board, MinoType};
+
+///stacks taller than this aren't searched, pco and most mid game pcs are four lines
+pub const PC_MAX_HEIGHT: usize = 4;
+///searches visiting more boards than this are treated as finding no pc, which keeps the
+///failed set bounded. a full preview on a four line board stays well under it
+const PC_MAX_NODES: usize = 100_000;
+
+///fewest pieces from hold and the preview that perfect clear a board, none if they can't.
+///queue is a placement's queue, hold first
+pub fn find_perfect_clear(board: &Board, queue: &[MinoType]) -> Option<usize> {
+    let height = get_height(board);
+    if height > PC_MAX_HEIGHT {
+        return None;
+    }
+    let board = to_board64(board);
+    let hold = queue.first().copied().and_then(to_shape);
+    let pieces: Vec<_> = queue.iter().skip(1).filter_map(|&p| to_shape(p)).collect();
+
+    //every piece goes below the pc height, so the empty cells there fix how many it takes
+    (height.max(1)..=PC_MAX_HEIGHT).find_map(|lines| {
+        let empty = empty_cells(&board, lines);
+        let needed = empty / 4;
+        if !empty.is_multiple_of(4) || needed > pieces.len() + hold.is_some() as usize {
+            return None;
+        }
+        let mut search = PcSearch {
+            pieces: &pieces,
+            failed: HashSet::new(),
+            nodes: 0,
+        };
+        search.dfs(board, hold, 0, lines).then_some(needed)
+    })
+}
+
+fn empty_cells(board: &Board64, lines: usize) -> usize {
+    (0..lines as i32)
+        .flat_map(|y| (0..10).map(move |x| Location { x, y }))
+        .filter(|&location| !board.is_occupied_at(location))
+        .count()
+}
+
+struct PcSearch<'a> {
+    pieces: &'a [Shape],
+    failed: HashSet<(Board64, Option<Shape>, usize, usize)>, //(board, hold, piece index, lines left)
+    nodes: usize,
+}
+
+impl PcSearch<'_> {
+    fn dfs(&mut self, board: Board64, hold: Option<Shape>, index: usize, lines: usize) -> bool {
+        if lines == 0 {
+            return true; //nothing goes above the pc height, so clearing it all empties the board
+        }
+        let pieces_left = self.pieces.len().saturating_sub(index) + hold.is_some() as usize;
+        if empty_cells(&board, lines) > pieces_left * 4 {
+            return false;
+        }
+        let key = (board, hold, index, lines);
+        if self.failed.contains(&key) || self.nodes >= PC_MAX_NODES {
+            return false;
+        }
+        self.nodes += 1;
+        let (current, hold) = match (self.pieces.get(index), hold) {
+            (Some(&current), hold) => (current, hold),
+            (None, Some(held)) => (held, None), //the preview ran out, the held piece goes last
+            (None, None) => return false,
+        };
+
+        let mut options = vec![(current, hold, index + 1)];
+        match hold {
+            Some(held) if held != current => options.push((held, Some(current), index + 1)),
+            None => {
+                if let Some(&next) = self.pieces.get(index + 1) {
+                    options.push((next, Some(current), index + 2));
+                }
+            }
+            _ => {}
+        }
+
+        for (shape, hold, next_index) in options {
+            let spawn = Piece::new(shape, Orientation::North)
+                .with(cc(4, 21))
+                .to_bl_placement();
+            if !spawn.is_in_free_space(&board) {
+                continue;
+            }
+            for placement in MoveRules::default().generate_minimized_moves(board, spawn) {
+                if placement
+                    .locations()
+                    .iter()
+                    .any(|location| location.y >= lines as i32)
+                {
+                    continue;
+                }
+                let mut placed = board;
+                let cleared = placement
+                    .place_on_and_clear_lines(&mut placed)
+                    .map_or(0, |cleared| cleared.count() as usize);
+                if self.dfs(placed, hold, next_index, lines - cleared) {
+                    return true;
+                }
+            }
+        }
+        self.failed.insert(key);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board_analyzer::board_from_rows;
+    use MinoType::{Empty, I, J, L, O, T};
+
+    #[test]
+    fn empty_board_takes_ten_is() {
+        let empty = board_from_rows(&[]);
+        assert_eq!(find_perfect_clear(&empty, &[I; 11]), Some(10));
+        assert_eq!(find_perfect_clear(&empty, &[I; 7]), None);
+    }
+
+    #[test]
+    fn four_line_setup_takes_its_last_three_pieces() {
+        let setup = board_from_rows(&["#######..."; 4]);
+        assert_eq!(find_perfect_clear(&setup, &[Empty, I, L, J]), Some(3));
+        assert_eq!(find_perfect_clear(&setup, &[Empty, J, I, L, O]), Some(3));
+        assert_eq!(find_perfect_clear(&setup, &[Empty, I, L]), None);
+    }
+
+    #[test]
+    fn parity_rules_out_odd_ts() {
+        //each t covers three cells of one checkerboard colour, three can't even out six of each
+        let gap = board_from_rows(&["......####"; 2]);
+        assert_eq!(find_perfect_clear(&gap, &[Empty, T, T, T]), None);
+        assert_eq!(find_perfect_clear(&gap, &[Empty, O, O, O]), Some(3));
+    }
+
+    #[test]
+    fn hold_counts_as_a_piece() {
+        let gap = board_from_rows(&["......####"; 2]);
+        assert_eq!(find_perfect_clear(&gap, &[O, O, O]), Some(3));
+        assert_eq!(find_perfect_clear(&gap, &[Empty, O, O]), None);
+        assert_eq!(find_perfect_clear(&gap, &[T, O, O]), None);
+    }
+}
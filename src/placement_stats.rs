@@ -1,15 +1,31 @@
 use std::time::SystemTime;
 
-use crate::board_analyzer::{get_garbage_height, get_height, get_well, has_cheese};
-use crate::replay_response::{ClearType, MinoType, PlacementStats};
-use crate::solver::solve_state;
+use crate::attack::get_surge_attack;
+use crate::board_analyzer::{
+    get_garbage_height, get_height, get_well, has_cheese, reconstruct_placement,
+};
+use crate::engine::Field;
+use crate::finesse::{optimal_keypresses, placement_cells};
+use crate::opener::classify_opener;
+use crate::pc_finder::{find_perfect_clear, PC_MAX_HEIGHT};
+use crate::replay_response::{ClearType, MinoType, PlacementStats, CLEAR_TYPES};
+use crate::solver::{solve_state, solver_pool, PendingGarbage, PREVIEW_PIECES};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::time::UNIX_EPOCH;
+
+///bump whenever a change here, in the solver or in the engine changes what gets computed,
+///cached stats stamped with another version are recomputed
+pub const STATS_VERSION: u32 = 4;
+
+///attack that counts as a spike, around two b2b clears
+const SPIKE_ATTACK: usize = 9;
+
 ///stats that represents the sum total of the data from several sequences of placements
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct CumulativePlacementStats {
     pub well_cols: [usize; 10],
-    pub clear_types: [usize; 16],
+    pub clear_types: [usize; CLEAR_TYPES],
     pub shape_types: [usize; 9],
     pub garbage_cleared: usize,
     pub lines_cleared: usize,
@@ -29,10 +45,35 @@ pub struct CumulativePlacementStats {
     pub opener_attack: usize,
     pub opener_frames: f64,
     pub opener_blocks: usize,
+    pub openers: [OpenerRecord; 6], //indexed by opener
     pub defense_potentials: Vec<usize>,
     pub blockfish_scores: Vec<usize>,
     pub spikable_boards: usize,
     pub pre_spike_boards: usize,
+    pub garbage_received: usize,
+    pub garbage_cancelled: usize, //equal to the attack spent cancelling
+    pub garbage_tanked: usize,
+    pub tanks: usize,              //placements that took garbage onto the board
+    pub finesse_placements: usize, //placements whose optimal keypresses could be worked out
+    pub finesse_faults: usize,
+    pub wasted_keypresses: usize,
+    pub finesse_fault_types: [[usize; 4]; 9], //indexed by shape then rotation
+    pub pc_opportunities: usize,              //times a pc became possible with the pieces in view
+    pub pcs_converted: usize,                 //opportunities the player went on to perfect clear
+    pub pc_pieces: usize,                     //pieces taken over the converted opportunities
+    #[serde(default)]
+    pub spike_annotations: Vec<Vec<SpikeAnnotation>>, //one list per game, merging keeps the games apart
+}
+
+///what the solver could send from a board against what the player went on to send
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpikeAnnotation {
+    pub placement: usize,
+    pub frame: f64, //frames into the game when the placement locked
+    pub solver_attack: usize,
+    pub actual_attack: usize, //over the pieces the solver could see
+    pub missed: bool,         //a spike was there and the player didn't send one
 }
 
 impl CumulativePlacementStats {
@@ -63,9 +104,31 @@ impl CumulativePlacementStats {
         self.opener_attack += stats.opener_attack;
         self.opener_frames += stats.opener_frames;
         self.opener_blocks += stats.opener_blocks;
+        self.openers
+            .iter_mut()
+            .zip(stats.openers.iter())
+            .for_each(|(c, s)| c.add(s));
 
         self.spikable_boards += stats.spikable_boards;
         self.pre_spike_boards += stats.pre_spike_boards;
+
+        self.garbage_received += stats.garbage_received;
+        self.garbage_cancelled += stats.garbage_cancelled;
+        self.garbage_tanked += stats.garbage_tanked;
+        self.tanks += stats.tanks;
+
+        self.finesse_placements += stats.finesse_placements;
+        self.finesse_faults += stats.finesse_faults;
+        self.wasted_keypresses += stats.wasted_keypresses;
+        self.finesse_fault_types
+            .iter_mut()
+            .flatten()
+            .zip(stats.finesse_fault_types.iter().flatten())
+            .for_each(|(c, s)| *c += s);
+
+        self.pc_opportunities += stats.pc_opportunities;
+        self.pcs_converted += stats.pcs_converted;
+        self.pc_pieces += stats.pc_pieces;
     }
     ///combine stats while consuming the other
     pub fn absorb(&mut self, stats: CumulativePlacementStats) {
@@ -79,9 +142,9 @@ impl CumulativePlacementStats {
 
         self.defense_potentials.extend(stats.defense_potentials);
         self.blockfish_scores.extend(stats.blockfish_scores);
+        self.spike_annotations.extend(stats.spike_annotations);
     }
     ///combine stats with a reference and cloning
-    #[allow(dead_code)]
     pub fn absorb_ref(&mut self, stats: &CumulativePlacementStats) {
         self.add_stats(stats);
 
@@ -94,6 +157,8 @@ impl CumulativePlacementStats {
         self.defense_potentials
             .extend(stats.defense_potentials.clone());
         self.blockfish_scores.extend(stats.blockfish_scores.clone());
+        self.spike_annotations
+            .extend(stats.spike_annotations.clone());
     }
 }
 
@@ -110,9 +175,34 @@ impl From<&[PlacementStats]> for CumulativePlacementStats {
         let mut opener_over = false;
 
         let mut current_combo = None;
-        let mut current_btb = None;
+        let mut current_btb: Option<BTBSegment> = None;
 
         let mut spike_grace_period = 0;
+        let empty_board = vec![MinoType::Empty; 400];
+        let attacks: Vec<usize> = game.iter().map(|p| p.attack.iter().sum()).collect();
+
+        let garbage = garbage_queue(game);
+
+        let lines: Vec<_> = solver_pool().install(|| {
+            game.par_iter()
+                .zip(&garbage)
+                .map(|(placement, (_, pending))| {
+                    solve_state(
+                        &placement.board,
+                        placement.btb_chain,
+                        placement.combo,
+                        &placement.queue,
+                        pending,
+                        &placement.ruleset,
+                    )
+                })
+                .collect()
+        });
+        //the solver is most of the work and each board is independent, so it runs ahead of the rest
+
+        let mut frame = 0.0;
+        let mut spikes = Vec::with_capacity(game.len());
+        let mut pc_window = None; //(placement it became possible, placement it could land by)
 
         for (i, placement) in game.iter().enumerate() {
             if !opener_over
@@ -138,6 +228,17 @@ impl From<&[PlacementStats]> for CumulativePlacementStats {
             let attack = placement.attack.iter().sum::<usize>();
             stats.attack += attack;
 
+            let received = placement.attack_received.iter().sum::<usize>();
+            let cancelled = garbage[i].0;
+            let tanked = placement.attack_tanked.iter().sum::<usize>();
+
+            stats.garbage_received += received;
+            stats.garbage_cancelled += cancelled;
+            stats.garbage_tanked += tanked;
+            if tanked > 0 {
+                stats.tanks += 1;
+            }
+
             if !opener_over {
                 stats.opener_blocks += 1;
                 stats.opener_attack += attack;
@@ -160,8 +261,52 @@ impl From<&[PlacementStats]> for CumulativePlacementStats {
             }
 
             stats.delays.push(round_delay(placement.frame_delay));
+            frame += placement.frame_delay;
             stats.keypresses += placement.keypresses;
 
+            let previous = if i > 0 { Some(&game[i - 1]) } else { None };
+            let previous_board = previous.map_or(&empty_board, |p| &p.board);
+            let placed = reconstruct_placement(
+                previous_board,
+                &placement.board,
+                placement.shape,
+                placement.attack_tanked.iter().sum(),
+            );
+            if let Some((optimal, rotation)) = placed.ok().and_then(|placed| {
+                optimal_keypresses(
+                    &Field::from_board(previous_board),
+                    placement.shape,
+                    placement.ruleset.height,
+                    placement_cells(&placed),
+                )
+            }) {
+                let held = previous.is_some_and(|p| p.queue.get(1) != Some(&placement.shape));
+                //the queue is left as it was after the previous placement, so its current piece tells if hold was used
+                let optimal = optimal + held as usize;
+                stats.finesse_placements += 1;
+                if placement.keypresses > optimal {
+                    stats.finesse_faults += 1;
+                    stats.wasted_keypresses += placement.keypresses - optimal;
+                    stats.finesse_fault_types[placement.shape as usize][rotation as usize] += 1;
+                }
+            }
+
+            if let Some((start, deadline)) = pc_window {
+                if placement.lines_cleared > 0 && height == 0 {
+                    stats.pcs_converted += 1;
+                    stats.pc_pieces += i - start;
+                    pc_window = None;
+                } else if i >= deadline {
+                    pc_window = None;
+                }
+            }
+            if pc_window.is_none() && height <= PC_MAX_HEIGHT {
+                if let Some(pieces) = find_perfect_clear(&placement.board, &placement.queue) {
+                    stats.pc_opportunities += 1;
+                    pc_window = Some((i, i + pieces));
+                }
+            }
+
             let garbage_height = get_garbage_height(&placement.board);
 
             stats.stack_heights.push(height - garbage_height);
@@ -187,7 +332,7 @@ impl From<&[PlacementStats]> for CumulativePlacementStats {
                     }
                 }
             } else if let Some(combo) = current_combo {
-                if combo.attack >= 9 {
+                if combo.attack >= SPIKE_ATTACK {
                     stats.pre_spike_boards = stats.pre_spike_boards.saturating_sub(combo.blocks);
                     spike_grace_period += 14; //if we just did a spike, we have 14 blocks of a grace period before we start penalizing not having a spike
                 }
@@ -196,7 +341,8 @@ impl From<&[PlacementStats]> for CumulativePlacementStats {
             }
 
             if placement.lines_cleared > 0 && !placement.clear_type.is_btb_clear() {
-                if let Some(btb) = current_btb {
+                if let Some(mut btb) = current_btb {
+                    btb.surge = get_surge_attack(&placement.ruleset, btb.btb);
                     stats.btb_segments.push(btb);
                     current_btb = None;
                 }
@@ -250,14 +396,19 @@ impl From<&[PlacementStats]> for CumulativePlacementStats {
                 }
             }
 
-            let (atk, def) = solve_state(
-                &placement.board,
-                placement.btb_chain,
-                placement.combo,
-                &placement.queue,
-            );
+            let (atk, def) = lines[i];
 
-            if atk >= 9 {
+            let window = (i + 1)..(i + 1 + PREVIEW_PIECES).min(game.len());
+            let actual_attack = attacks[window].iter().sum();
+            spikes.push(SpikeAnnotation {
+                placement: i,
+                frame,
+                solver_attack: atk,
+                actual_attack,
+                missed: atk >= SPIKE_ATTACK && actual_attack < SPIKE_ATTACK,
+            });
+
+            if atk >= SPIKE_ATTACK {
                 //spikable board limit is around 2btb clears
                 stats.spikable_boards += 1;
             } else {
@@ -301,10 +452,40 @@ impl From<&[PlacementStats]> for CumulativePlacementStats {
         if let Some(current_btb) = current_btb {
             stats.btb_segments.push(current_btb);
         }
+        stats.spike_annotations.push(spikes);
+
+        let opener_placements = &game[..stats.opener_blocks];
+        let opener = classify_opener(opener_placements);
+        stats.openers[opener as usize] = OpenerRecord {
+            games: 1,
+            successes: opener_placements
+                .iter()
+                .any(|placement| opener.is_payoff(placement)) as usize,
+            attack: stats.opener_attack,
+            frames: stats.opener_frames,
+        };
 
         stats
     }
 }
+
+///how the games that started with one opener went
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct OpenerRecord {
+    pub games: usize,
+    pub successes: usize, //games where the setup was cashed in before the opener ended
+    pub attack: usize,
+    pub frames: f64,
+}
+
+impl OpenerRecord {
+    fn add(&mut self, record: &OpenerRecord) {
+        self.games += record.games;
+        self.successes += record.successes;
+        self.attack += record.attack;
+        self.frames += record.frames;
+    }
+}
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 
 pub struct BTBSegment {
@@ -321,6 +502,7 @@ pub struct BTBSegment {
     pub t_placed: usize,
 
     pub well: Option<usize>,
+    pub surge: usize, //attack released when the chain broke, 0 without surge rules
 }
 
 impl BTBSegment {
@@ -339,6 +521,7 @@ impl BTBSegment {
             t_placed: (shape == MinoType::T) as usize,
 
             well,
+            surge: 0,
         }
     }
 }
@@ -388,6 +571,177 @@ fn mino_to_color(mino: MinoType) -> Option<blockfish::Color> {
     }
 }
 
+///per placement, the garbage its attack cancelled and what is still pending after it
+pub fn garbage_queue(game: &[PlacementStats]) -> Vec<(usize, Vec<PendingGarbage>)> {
+    let mut pending_garbage = Vec::new();
+    game.iter()
+        .map(|placement| {
+            pending_garbage.extend(placement.attack_received.iter().enumerate().map(
+                |(j, &amount)| PendingGarbage {
+                    amount,
+                    column: placement.garbage_columns.get(j).copied().flatten(),
+                },
+            ));
+            let cancelled = take_garbage(&mut pending_garbage, placement.attack.iter().sum());
+            take_garbage(&mut pending_garbage, placement.attack_tanked.iter().sum());
+            //attack cancels the queue before anything is tanked, the rest is sent through
+            (cancelled, pending_garbage.clone())
+        })
+        .collect()
+}
+
+///removes up to `lines` from the front of the garbage queue, returning how many there were
+fn take_garbage(pending: &mut Vec<PendingGarbage>, mut lines: usize) -> usize {
+    let mut taken = 0;
+    while lines > 0 {
+        let Some(front) = pending.first_mut() else {
+            break;
+        };
+        let amount = front.amount.min(lines);
+        front.amount -= amount;
+        lines -= amount;
+        taken += amount;
+        if front.amount == 0 {
+            pending.remove(0);
+        }
+    }
+    taken
+}
+
 fn round_delay(delay: f64) -> f64 {
     (delay * 10.0).round() / 10.0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board_analyzer::board_from_rows;
+    use crate::player_stats::PlayerStats;
+    use crate::replay_response::placement_on;
+    use crate::ruleset::Ruleset;
+
+    ///a placement onto `rows` with a short queue, enough for the solver and blockfish
+    fn placement(rows: &[&str]) -> PlacementStats {
+        PlacementStats {
+            queue: vec![MinoType::T, MinoType::I],
+            ..placement_on(board_from_rows(rows))
+        }
+    }
+
+    #[test]
+    fn received_and_tanked_garbage_is_aggregated() {
+        let game = [
+            PlacementStats {
+                attack_received: vec![4, 2],
+                ..placement(&[])
+            },
+            PlacementStats {
+                attack: vec![3],
+                ..placement(&[])
+            },
+            PlacementStats {
+                attack_tanked: vec![1],
+                ..placement(&["#########."])
+            },
+            PlacementStats {
+                attack_tanked: vec![2],
+                ..placement(&["#########.", "#########.", "#########."])
+            },
+        ];
+        let stats = CumulativePlacementStats::from(game.as_slice());
+        assert_eq!(stats.garbage_received, 6);
+        assert_eq!(stats.garbage_cancelled, 3);
+        assert_eq!(stats.garbage_tanked, 3);
+        assert_eq!(stats.tanks, 2);
+
+        let mut merged = stats.clone();
+        merged.absorb_ref(&stats);
+        assert_eq!(merged.garbage_received, 12);
+        assert_eq!(merged.tanks, 4);
+
+        let player = PlayerStats::from(&merged);
+        assert_eq!(player.cancel_rate, 0.5);
+        assert_eq!(player.tank_rate, 0.5);
+        assert_eq!(player.average_tank, 1.5);
+        assert_eq!(player.cancel_efficiency, 1.0);
+    }
+
+    #[test]
+    fn finesse_faults_count_keys_past_the_optimal_and_hold() {
+        let game = [
+            PlacementStats {
+                keypresses: 1,
+                queue: vec![MinoType::Empty, MinoType::O, MinoType::Z],
+                ..placement_on(board_from_rows(&["....T.....", "...TTT...."]))
+            },
+            PlacementStats {
+                shape: MinoType::O,
+                keypresses: 4, //two more than das left and drop
+                queue: vec![MinoType::Empty, MinoType::Z, MinoType::I],
+                ..placement_on(board_from_rows(&["OO..T.....", "OO.TTT...."]))
+            },
+            PlacementStats {
+                shape: MinoType::I,
+                keypresses: 3, //hold, das right and drop
+                queue: vec![MinoType::Z, MinoType::J, MinoType::L],
+                ..placement_on(board_from_rows(&["OO..T.....", "OO.TTTIIII"]))
+            },
+        ];
+        let stats = CumulativePlacementStats::from(game.as_slice());
+        assert_eq!(stats.finesse_placements, 3);
+        assert_eq!(stats.finesse_faults, 1);
+        assert_eq!(stats.wasted_keypresses, 2);
+        assert_eq!(
+            stats.finesse_fault_types[MinoType::O as usize],
+            [1, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn breaking_a_long_chain_releases_its_surge() {
+        let clear = |clear_type: ClearType, lines_cleared: usize, attack: usize| PlacementStats {
+            shape: MinoType::I,
+            lines_cleared,
+            clear_type,
+            attack: vec![attack],
+            ruleset: Ruleset::tetrio_s2(),
+            ..placement(&[])
+        };
+        let mut game = vec![clear(ClearType::Quad, 4, 4)];
+        game.extend((0..4).map(|_| clear(ClearType::Quad, 4, 5)));
+        game.push(clear(ClearType::Single, 1, 4)); //no attack of its own, only the surge
+        let stats = CumulativePlacementStats::from(game.as_slice());
+        let [segment] = stats.btb_segments.as_slice() else {
+            panic!("one chain expected, got {}", stats.btb_segments.len());
+        };
+        assert_eq!(segment.btb, 4);
+        assert_eq!(segment.surge, 4);
+
+        let player = PlayerStats::from(&stats);
+        assert_eq!(player.surges, 1);
+        assert_eq!(player.average_surge, 4.0);
+        assert_eq!(player.surge_attack_share, 4.0 / 28.0);
+    }
+
+    #[test]
+    fn untaken_spikes_are_annotated_as_missed() {
+        let well = ["#########."; 4]; //an I there is a quad into a perfect clear
+        let game = [placement(&well), placement(&[])];
+        let stats = CumulativePlacementStats::from(game.as_slice());
+        let [spikes] = stats.spike_annotations.as_slice() else {
+            panic!("one game expected, got {}", stats.spike_annotations.len());
+        };
+        assert_eq!(spikes.len(), 2);
+        assert!(spikes[0].solver_attack >= SPIKE_ATTACK);
+        assert_eq!(spikes[0].actual_attack, 0);
+        assert!(spikes[0].missed);
+        assert!(!spikes[1].missed);
+
+        let mut merged = stats.clone();
+        merged.absorb_ref(&stats);
+        let cached: CumulativePlacementStats =
+            serde_json::from_str(&serde_json::to_string(&merged).unwrap()).unwrap();
+        assert_eq!(cached.spike_annotations.len(), 2); //kept per game through merges and the cache
+        assert_eq!(PlayerStats::from(&cached).spikes.len(), 2);
+    }
+}
@@ -1,8 +1,9 @@
 use std::collections::HashMap;
 
 use crate::{
-    placement_stats::CumulativePlacementStats,
-    replay_response::{ClearType, MinoType},
+    opener::Opener,
+    placement_stats::{CumulativePlacementStats, SpikeAnnotation},
+    replay_response::{ClearType, MinoType, CLEAR_TYPES},
 };
 use serde::Serialize;
 
@@ -33,6 +34,7 @@ pub struct PlayerStats {
     pub apm: f64,
     pub opener_apm: f64,
     pub midgame_apm: f64,
+    pub openers: HashMap<Opener, OpenerStats>, //only openers that were played
 
     pub pps: f64,
     pub opener_pps: f64,
@@ -49,6 +51,11 @@ pub struct PlayerStats {
     pub max_btb: usize,
     pub max_btb_attack: usize,
 
+    pub surges: usize,
+    pub surge_frequency: f64, //surges per minute
+    pub average_surge: f64,
+    pub surge_attack_share: f64,
+
     pub combo_chain_efficiency: f64,
     pub combo_chain: f64,
     pub combo_chain_apm: f64,
@@ -59,7 +66,7 @@ pub struct PlayerStats {
     pub max_combo_attack: usize,
 
     pub average_spike_potential: f64,
-    pub average_defence_potential: f64,
+    pub average_defence_potential: f64, //lines of garbage that could be cancelled or tanked within the preview
 
     pub pps_variance: f64,
 
@@ -68,13 +75,58 @@ pub struct PlayerStats {
     pub burst_pps: f64,
     pub attack_delay_rate: f64,
     pub pre_attack_delay_rate: f64,
+
+    pub garbage_received: usize,
+    pub garbage_received_pm: f64,
+    pub cancel_rate: f64,
+    pub tank_rate: f64,
+    pub average_tank: f64,
+    pub cancel_efficiency: f64, //share of attack spent cancelling instead of being sent
+
+    pub finesse_faults: usize,
+    pub finesse_fault_rate: f64,
+    pub wasted_keypresses: usize,
+    pub wasted_kpp: f64,
+    pub finesse_misplays: Vec<FinesseMisplay>, //most faulted first
+
+    pub pc_opportunities: usize,
+    pub pc_conversion_rate: f64,
+    pub average_pieces_to_pc: f64,
+
+    pub spikes: Vec<Vec<SpikeAnnotation>>, //per game one per placement, for marking missed spikes on a timeline
 }
+
+#[derive(Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenerStats {
+    pub games: usize,
+    pub apm: f64,
+    pub success_rate: f64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FinesseMisplay {
+    pub shape: MinoType,
+    pub rotation: u8, //clockwise quarter turns from spawn, the lower of two covering the same cells
+    pub faults: usize,
+}
+
 #[derive(Debug, Clone, Copy)]
 struct Burst {
     blocks: usize,
     delay: f64,
 }
 
+///a count over another, 0 rather than NaN when there was nothing to count over
+fn ratio(count: usize, over: usize) -> f64 {
+    if over == 0 {
+        0.0
+    } else {
+        count as f64 / over as f64
+    }
+}
+
 impl From<&CumulativePlacementStats> for PlayerStats {
     fn from(stats: &CumulativePlacementStats) -> Self {
         let tspins = stats.clear_types[ClearType::TspinDouble as usize]
@@ -145,9 +197,17 @@ impl From<&CumulativePlacementStats> for PlayerStats {
             .map(|segment| segment.attack)
             .sum::<usize>() as f64;
 
+        let surges: Vec<_> = stats
+            .btb_segments
+            .iter()
+            .map(|segment| segment.surge)
+            .filter(|&surge| surge > 0)
+            .collect();
+        let surge_attack = surges.iter().sum::<usize>();
+
         let mut clear_types = HashMap::new();
 
-        for clear_type in 0..16 {
+        for clear_type in 0..CLEAR_TYPES as u8 {
             clear_types.insert(
                 ClearType::try_from(clear_type).unwrap(),
                 stats.clear_types[clear_type as usize],
@@ -196,6 +256,40 @@ impl From<&CumulativePlacementStats> for PlayerStats {
         }
         //bursts defined as segments that are 1 sd below the average
 
+        let openers = Opener::ALL
+            .iter()
+            .filter(|&&opener| stats.openers[opener as usize].games > 0)
+            .map(|&opener| {
+                let record = &stats.openers[opener as usize];
+                (
+                    opener,
+                    OpenerStats {
+                        games: record.games,
+                        apm: record.attack as f64 / record.frames * 3600.0,
+                        success_rate: record.successes as f64 / record.games as f64,
+                    },
+                )
+            })
+            .collect();
+
+        let mut finesse_misplays: Vec<_> = stats
+            .finesse_fault_types
+            .iter()
+            .enumerate()
+            .flat_map(|(shape, rotations)| {
+                rotations
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &faults)| faults > 0)
+                    .map(move |(rotation, &faults)| FinesseMisplay {
+                        shape: MinoType::try_from(shape as u8).unwrap(),
+                        rotation: rotation as u8,
+                        faults,
+                    })
+            })
+            .collect();
+        finesse_misplays.sort_by_key(|misplay| std::cmp::Reverse(misplay.faults));
+
         let prev_attack_chains: Vec<_> =
             attack_chains.iter().filter_map(|c| c.prev_delay).collect();
 
@@ -229,6 +323,7 @@ impl From<&CumulativePlacementStats> for PlayerStats {
             midgame_apm: ((stats.attack - stats.opener_attack) as f64
                 / (time_secs - opener_time_secs))
                 * 60.0,
+            openers,
             opener_pps: stats.opener_blocks as f64 / opener_time_secs,
             midgame_pps: (blocks - stats.opener_blocks as f64) / (time_secs - opener_time_secs),
             pps: blocks / time_secs,
@@ -256,6 +351,10 @@ impl From<&CumulativePlacementStats> for PlayerStats {
                 .map(|segment| segment.attack)
                 .max()
                 .unwrap_or(0),
+            surges: surges.len(),
+            surge_frequency: surges.len() as f64 * 60.0 / time_secs,
+            average_surge: ratio(surge_attack, surges.len()),
+            surge_attack_share: ratio(surge_attack, stats.attack),
             combo_chain_efficiency: true_combo_chains.len() as f64
                 / stats.combo_segments.len() as f64,
             combo_chain: true_combo_chains
@@ -299,6 +398,57 @@ impl From<&CumulativePlacementStats> for PlayerStats {
                 / (prev_attack_chains.len() as f64),
             burst_pps: bursts.iter().map(|burst| burst.blocks).sum::<usize>() as f64
                 / (bursts.iter().map(|burst| burst.delay).sum::<f64>() / 60.0),
+            garbage_received: stats.garbage_received,
+            garbage_received_pm: stats.garbage_received as f64 * 60.0 / time_secs,
+            cancel_rate: ratio(stats.garbage_cancelled, stats.garbage_received),
+            tank_rate: ratio(stats.garbage_tanked, stats.garbage_received),
+            average_tank: ratio(stats.garbage_tanked, stats.tanks),
+            cancel_efficiency: ratio(stats.garbage_cancelled, stats.attack),
+            finesse_faults: stats.finesse_faults,
+            finesse_fault_rate: ratio(stats.finesse_faults, stats.finesse_placements),
+            wasted_keypresses: stats.wasted_keypresses,
+            wasted_kpp: ratio(stats.wasted_keypresses, stats.finesse_placements),
+            finesse_misplays,
+            pc_opportunities: stats.pc_opportunities,
+            pc_conversion_rate: ratio(stats.pcs_converted, stats.pc_opportunities),
+            average_pieces_to_pc: ratio(stats.pc_pieces, stats.pcs_converted),
+            spikes: stats.spike_annotations.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::placement_stats::OpenerRecord;
+
+    #[test]
+    fn only_played_openers_are_reported() {
+        let mut stats = CumulativePlacementStats::default();
+        stats.openers[Opener::Tki as usize] = OpenerRecord {
+            games: 2,
+            successes: 1,
+            attack: 8,
+            frames: 240.0,
+        };
+        let openers = PlayerStats::from(&stats).openers;
+        assert_eq!(openers.len(), 1);
+        assert_eq!(openers[&Opener::Tki].apm, 120.0);
+        assert_eq!(openers[&Opener::Tki].success_rate, 0.5);
+    }
+
+    #[test]
+    fn rates_over_nothing_are_zero() {
+        let stats = PlayerStats::from(&CumulativePlacementStats::default());
+        assert_eq!(stats.cancel_rate, 0.0);
+        assert_eq!(stats.tank_rate, 0.0);
+        assert_eq!(stats.average_tank, 0.0);
+        assert_eq!(stats.cancel_efficiency, 0.0);
+        assert_eq!(stats.finesse_fault_rate, 0.0);
+        assert_eq!(stats.wasted_kpp, 0.0);
+        assert_eq!(stats.average_surge, 0.0);
+        assert_eq!(stats.surge_attack_share, 0.0);
+        assert_eq!(stats.pc_conversion_rate, 0.0);
+        assert_eq!(stats.average_pieces_to_pc, 0.0);
+    }
+}
@@ -0,0 +1,533 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::engine::{Game, GameOptions, Handling, Key};
+use crate::replay_response::{PlacementStats, SpinRules};
+use crate::ruleset::Ruleset;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Unsupported,
+    Unparsable,
+}
+
+impl std::error::Error for DecodeError {}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::Unsupported => f.write_str("replay format is unsupported"),
+            DecodeError::Unparsable => f.write_str("replay is not a valid ttr or ttrm"),
+        }
+    }
+}
+
+///placement sequences of one player, a game is none if its events could not be replayed
+#[derive(Debug)]
+pub struct DecodedPlayer {
+    pub name: String,
+    pub games: Vec<Option<Vec<PlacementStats>>>,
+}
+
+#[derive(Deserialize)]
+struct ReplayEvent {
+    #[serde(default)]
+    frame: f64,
+    #[serde(rename = "type")]
+    event_type: String,
+    #[serde(default)]
+    data: Value,
+}
+
+#[derive(Deserialize)]
+struct ReplayData {
+    #[serde(default)]
+    events: Vec<ReplayEvent>,
+    #[serde(default)]
+    options: Option<Value>, //season 2 keeps the options here instead of in a full event
+}
+
+#[derive(Deserialize)]
+struct ReplayUser {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct ReplayBoard {
+    user: ReplayUser,
+}
+
+#[derive(Deserialize)]
+struct ReplayRound {
+    #[serde(default)]
+    board: Vec<ReplayBoard>,
+    replays: Vec<ReplayData>,
+}
+
+///one player's game of a season 2 round
+#[derive(Deserialize)]
+struct RoundReplay {
+    username: String,
+    replay: ReplayData,
+}
+
+#[derive(Deserialize)]
+struct LeagueReplay {
+    rounds: Vec<Vec<RoundReplay>>,
+}
+
+fn push_game(players: &mut Vec<DecodedPlayer>, name: String, game: Option<Vec<PlacementStats>>) {
+    match players.iter_mut().find(|player| player.name == name) {
+        Some(player) => player.games.push(game),
+        None => players.push(DecodedPlayer {
+            name,
+            games: vec![game],
+        }),
+    }
+}
+
+///decodes a .ttr or .ttrm replay into the placements of every player in it
+pub fn decode_replay(replay: &str) -> Result<Vec<DecodedPlayer>, DecodeError> {
+    let replay: Value = serde_json::from_str(replay).or(Err(DecodeError::Unparsable))?;
+
+    if let Some(container) = replay.get("replay") {
+        return decode_container(&replay, container);
+    }
+
+    let data = replay.get("data").ok_or(DecodeError::Unparsable)?;
+    let mut players: Vec<DecodedPlayer> = Vec::new();
+
+    if data.is_array() {
+        let rounds: Vec<ReplayRound> =
+            serde_json::from_value(data.clone()).or(Err(DecodeError::Unparsable))?;
+        for round in rounds {
+            for (i, replay) in round.replays.iter().enumerate() {
+                let name = username(&replay.events)
+                    .or_else(|| round.board.get(i).map(|board| board.user.username.clone()))
+                    .ok_or(DecodeError::Unparsable)?;
+                push_game(&mut players, name, decode_game(replay)?);
+            }
+        }
+    } else {
+        let data: ReplayData =
+            serde_json::from_value(data.clone()).or(Err(DecodeError::Unparsable))?;
+        let name = replay
+            .pointer("/user/username")
+            .and_then(|name| name.as_str())
+            .map(|name| name.to_string())
+            .or_else(|| username(&data.events))
+            .ok_or(DecodeError::Unparsable)?;
+        players.push(DecodedPlayer {
+            name,
+            games: vec![decode_game(&data)?],
+        });
+    }
+
+    if players.is_empty() {
+        return Err(DecodeError::Unparsable);
+    }
+    Ok(players)
+}
+
+///season 2 replays, rounds of per player replays in a ttrm or the one replay of a ttr
+fn decode_container(replay: &Value, container: &Value) -> Result<Vec<DecodedPlayer>, DecodeError> {
+    let mut players: Vec<DecodedPlayer> = Vec::new();
+    if container.get("rounds").is_some() {
+        let league: LeagueReplay =
+            serde_json::from_value(container.clone()).or(Err(DecodeError::Unparsable))?;
+        for round in league.rounds {
+            for player in round {
+                push_game(&mut players, player.username, decode_game(&player.replay)?);
+            }
+        }
+    } else if container.get("events").is_some() {
+        let data: ReplayData =
+            serde_json::from_value(container.clone()).or(Err(DecodeError::Unparsable))?;
+        let name = replay
+            .pointer("/users/0/username")
+            .and_then(|name| name.as_str())
+            .map(|name| name.to_string())
+            .ok_or(DecodeError::Unparsable)?;
+        players.push(DecodedPlayer {
+            name,
+            games: vec![decode_game(&data)?],
+        });
+    } else {
+        return Err(DecodeError::Unsupported);
+    }
+
+    if players.is_empty() {
+        return Err(DecodeError::Unparsable);
+    }
+    Ok(players)
+}
+
+///username of each round's winner as the replay records it, none for a round it doesn't settle.
+///season 1 marks the winner's board with success, season 2 marks whoever is still alive
+pub fn round_winners(replay: &str) -> Vec<Option<String>> {
+    let Ok(replay) = serde_json::from_str::<Value>(replay) else {
+        return Vec::new();
+    };
+    let (rounds, players, flag) = match replay.pointer("/replay/rounds") {
+        Some(rounds) => (rounds, None, "alive"),
+        None => match replay.get("data") {
+            Some(rounds) => (rounds, Some("board"), "success"),
+            None => return Vec::new(),
+        },
+    };
+    let Some(rounds) = rounds.as_array() else {
+        return Vec::new();
+    };
+    rounds
+        .iter()
+        .map(|round| {
+            let players = match players {
+                Some(key) => round.get(key)?,
+                None => round,
+            };
+            let mut winners = players
+                .as_array()?
+                .iter()
+                .filter(|player| player.get(flag).and_then(|v| v.as_bool()) == Some(true));
+            let winner = winners.next()?;
+            if winners.next().is_some() {
+                return None;
+            }
+            winner
+                .pointer("/user/username")
+                .or_else(|| winner.get("username"))
+                .and_then(|name| name.as_str())
+                .map(|name| name.to_string())
+        })
+        .collect()
+}
+
+fn username(events: &[ReplayEvent]) -> Option<String> {
+    events
+        .iter()
+        .find(|event| event.event_type == "full")
+        .and_then(|event| event.data.pointer("/options/username"))
+        .and_then(|name| name.as_str())
+        .map(|name| name.to_string())
+}
+
+///tetrio's spinbonuses option, rules the engine can't score make the replay unsupported.
+///"all", "all+" and "stupid" score every piece's spins as full ones, which isn't all-mini
+fn spin_rules(spinbonuses: Option<&str>, default: SpinRules) -> Result<SpinRules, DecodeError> {
+    match spinbonuses {
+        None => Ok(default),
+        Some("T-spins" | "handheld") => Ok(SpinRules::TSpins), //handheld only scores t-spins too
+        Some("all-mini" | "all-mini+") => Ok(SpinRules::AllMini),
+        Some("none") => Ok(SpinRules::None),
+        Some(_) => Err(DecodeError::Unsupported),
+    }
+}
+
+///reads the options of the full event, none for a game without a seed as it can't be replayed
+fn game_options(full: &Value) -> Result<Option<GameOptions>, DecodeError> {
+    let Some(options) = full.get("options") else {
+        return Ok(None);
+    };
+    let number = |key: &str| options.get(key).and_then(|v| v.as_f64());
+    let defaults = GameOptions::default();
+    let ruleset = match options.get("b2bcharging").and_then(|v| v.as_bool()) {
+        Some(true) => Ruleset::tetrio_s2(),
+        _ => defaults.ruleset,
+    }; //b2b charging is season 2's surge
+    let handling = options
+        .get("handling")
+        .or_else(|| full.pointer("/game/handling"));
+    let handling_number = |key: &str| {
+        handling
+            .and_then(|handling| handling.get(key))
+            .and_then(|v| v.as_f64())
+    };
+
+    let spin_rules = spin_rules(
+        options.get("spinbonuses").and_then(|v| v.as_str()),
+        ruleset.spin_rules,
+    )?;
+    let Some(seed) = number("seed") else {
+        return Ok(None);
+    };
+
+    let options = GameOptions {
+        seed: seed as u64,
+        gravity: number("g").unwrap_or(defaults.gravity),
+        gravity_increase: number("gincrease").unwrap_or(defaults.gravity_increase),
+        gravity_margin: number("gmargin").unwrap_or(defaults.gravity_margin),
+        lock_time: number("locktime").unwrap_or(defaults.lock_time),
+        lock_resets: number("lockresets")
+            .map(|v| v as usize)
+            .unwrap_or(defaults.lock_resets),
+        garbage_speed: number("garbagespeed").unwrap_or(defaults.garbage_speed),
+        garbage_cap: number("garbagecap")
+            .map(|v| v as usize)
+            .unwrap_or(defaults.garbage_cap),
+        messiness_change: number("messiness_change").unwrap_or(defaults.messiness_change),
+        messiness_inner: number("messiness_inner").unwrap_or(defaults.messiness_inner),
+        next_count: number("nextcount")
+            .map(|v| v as usize)
+            .unwrap_or(defaults.next_count),
+        handling: Handling {
+            arr: handling_number("arr").unwrap_or(defaults.handling.arr),
+            das: handling_number("das").unwrap_or(defaults.handling.das),
+            sdf: handling_number("sdf").unwrap_or(defaults.handling.sdf),
+        },
+        ruleset: Ruleset {
+            pc_bonus: number("allclear_garbage")
+                .map(|v| v as usize)
+                .unwrap_or(ruleset.pc_bonus),
+            garbage_multiplier: number("garbagemultiplier").unwrap_or(ruleset.garbage_multiplier),
+            spin_rules,
+            width: number("boardwidth")
+                .map(|v| v as usize)
+                .unwrap_or(ruleset.width),
+            height: number("boardheight")
+                .map(|v| v as usize)
+                .unwrap_or(ruleset.height),
+            ..ruleset
+        },
+    };
+    if !options.ruleset.fits_board() {
+        return Err(DecodeError::Unsupported);
+    } //a board that isn't 10 wide can't be simulated or stored
+    Ok(Some(options))
+}
+
+///incoming garbage from an in game event, (amount, hole column)
+fn incoming_garbage(ige: &Value) -> Option<(usize, Option<usize>)> {
+    let column = |data: &Value| {
+        data.get("column")
+            .and_then(|v| v.as_u64())
+            .map(|v| v as usize)
+    };
+    if ige.get("type").and_then(|v| v.as_str()) == Some("garbage") {
+        return Some((ige.get("amt")?.as_u64()? as usize, column(ige)));
+    } //season 2 puts garbage straight in the event
+    let interaction = ige.get("data")?;
+    match interaction.get("type")?.as_str()? {
+        "interaction" => {
+            let garbage = interaction.get("data")?;
+            if garbage.get("type")?.as_str()? != "garbage" {
+                return None;
+            }
+            Some((garbage.get("amt")?.as_u64()? as usize, column(garbage)))
+        }
+        "attack" => Some((
+            interaction.get("lines")?.as_u64()? as usize,
+            column(interaction),
+        )),
+        _ => None,
+    }
+}
+
+///replays the event stream of one game, none if the game can't be reconstructed
+fn decode_game(data: &ReplayData) -> Result<Option<Vec<PlacementStats>>, DecodeError> {
+    let full = match &data.options {
+        Some(options) => json!({ "options": options }),
+        None => match data.events.iter().find(|event| event.event_type == "full") {
+            Some(full) => full.data.clone(),
+            None => return Ok(None),
+        },
+    };
+    let Some(options) = game_options(&full)? else {
+        return Ok(None);
+    };
+    let mut game = Game::new(options);
+
+    for event in &data.events {
+        let subframe = event
+            .data
+            .get("subframe")
+            .and_then(|v| v.as_f64())
+            .unwrap_or(0.0);
+        game.advance_to(event.frame + subframe);
+        if game.is_over() {
+            break;
+        }
+        match event.event_type.as_str() {
+            "keydown" | "keyup" => {
+                let Some(key) = event
+                    .data
+                    .get("key")
+                    .and_then(|v| v.as_str())
+                    .and_then(Key::parse)
+                else {
+                    continue;
+                };
+                if event.event_type == "keydown" {
+                    game.key_down(key);
+                } else {
+                    game.key_up(key);
+                }
+            }
+            "ige" => {
+                if let Some((amount, column)) = incoming_garbage(&event.data) {
+                    game.receive_garbage(amount, column);
+                }
+            }
+            "end" => break,
+            _ => {}
+        }
+    }
+
+    let placements = game.into_placements();
+    if placements.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(placements))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruleset::B2bRules;
+
+    ///the inner replay of a season 2 game whose only input hard drops the first piece
+    fn season2_game(options: Value) -> Value {
+        json!({
+            "options": options,
+            "events": [
+                {"frame": 0, "type": "start", "data": {}},
+                {"frame": 10, "type": "keydown", "data": {"key": "hardDrop", "subframe": 0.0}},
+                {"frame": 10, "type": "keyup", "data": {"key": "hardDrop", "subframe": 0.0}},
+                {"frame": 20, "type": "end", "data": {}},
+            ],
+        })
+    }
+
+    fn season2_ttr(options: Value) -> String {
+        json!({
+            "users": [{"username": "muncher"}],
+            "replay": season2_game(options),
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn round_winners_follow_the_replay() {
+        let season1 = json!({"data": [
+            {"board": [
+                {"user": {"username": "left"}, "success": false},
+                {"user": {"username": "right"}, "success": true},
+            ]},
+            {"board": [
+                {"user": {"username": "left"}, "success": true},
+                {"user": {"username": "right"}, "success": false},
+            ]},
+            {"board": [
+                {"user": {"username": "left"}, "success": false},
+                {"user": {"username": "right"}, "success": false},
+            ]},
+        ]});
+        assert_eq!(
+            round_winners(&season1.to_string()),
+            [Some("right".to_string()), Some("left".to_string()), None]
+        );
+
+        let season2 = json!({"replay": {"rounds": [[
+            {"username": "left", "alive": true},
+            {"username": "right", "alive": false},
+        ]]}});
+        assert_eq!(
+            round_winners(&season2.to_string()),
+            [Some("left".to_string())]
+        );
+        assert!(round_winners(&season2_ttr(json!({"seed": 1}))).is_empty());
+    }
+
+    #[test]
+    fn decodes_season2_ttr() {
+        let players = decode_replay(&season2_ttr(json!({"seed": 1}))).unwrap();
+        assert_eq!(players.len(), 1);
+        assert_eq!(players[0].name, "muncher");
+        assert_eq!(players[0].games[0].as_ref().map(Vec::len), Some(1));
+    }
+
+    #[test]
+    fn decodes_season2_ttrm_rounds() {
+        let round = |seed: u64| {
+            json!([
+                {"username": "left", "replay": season2_game(json!({"seed": seed}))},
+                {"username": "right", "replay": season2_game(json!({"seed": seed}))},
+            ])
+        };
+        let replay = json!({"replay": {"rounds": [round(1), round(2)]}}).to_string();
+        let players = decode_replay(&replay).unwrap();
+        assert_eq!(players.len(), 2);
+        for player in players {
+            assert_eq!(player.games.len(), 2);
+            assert!(player.games.iter().all(Option::is_some));
+        }
+    }
+
+    fn decoded_ruleset(options: Value) -> Result<Ruleset, DecodeError> {
+        let players = decode_replay(&season2_ttr(options))?;
+        let placements = players[0].games[0].as_ref().unwrap();
+        Ok(placements[0].ruleset)
+    }
+
+    #[test]
+    fn spinbonuses_map_to_spin_rules() {
+        for (spinbonuses, expected) in [
+            ("T-spins", SpinRules::TSpins),
+            ("all-mini", SpinRules::AllMini),
+            ("all-mini+", SpinRules::AllMini),
+            ("handheld", SpinRules::TSpins),
+            ("none", SpinRules::None),
+        ] {
+            let ruleset = decoded_ruleset(json!({"seed": 1, "spinbonuses": spinbonuses})).unwrap();
+            assert_eq!(ruleset.spin_rules, expected, "{spinbonuses}");
+        }
+        let ruleset = decoded_ruleset(json!({"seed": 1})).unwrap();
+        assert_eq!(ruleset.spin_rules, SpinRules::TSpins);
+    }
+
+    #[test]
+    fn full_allspins_are_unsupported() {
+        for spinbonuses in ["all", "all+", "stupid"] {
+            let decoded = decoded_ruleset(json!({"seed": 1, "spinbonuses": spinbonuses}));
+            assert!(
+                matches!(decoded, Err(DecodeError::Unsupported)),
+                "{spinbonuses}"
+            );
+        }
+    }
+
+    #[test]
+    fn board_size_comes_from_the_options() {
+        let ruleset = decoded_ruleset(json!({"seed": 1})).unwrap();
+        assert_eq!((ruleset.width, ruleset.height), (10, 20));
+        let tall = decoded_ruleset(json!({"seed": 1, "boardheight": 25})).unwrap();
+        assert_eq!((tall.width, tall.height), (10, 25));
+        for options in [
+            json!({"seed": 1, "boardwidth": 12}),
+            json!({"seed": 1, "boardwidth": 4}),
+            json!({"seed": 1, "boardheight": 40}),
+        ] {
+            assert!(
+                matches!(decoded_ruleset(options.clone()), Err(DecodeError::Unsupported)),
+                "{options}"
+            );
+        }
+    }
+
+    #[test]
+    fn b2bcharging_picks_surge() {
+        let charging = decoded_ruleset(json!({"seed": 1, "b2bcharging": true})).unwrap();
+        assert_eq!(charging.b2b, B2bRules::Surge);
+        assert_eq!(charging.spin_rules, SpinRules::AllMini);
+        let chaining = decoded_ruleset(json!({"seed": 1, "b2bcharging": false})).unwrap();
+        assert_eq!(chaining.b2b, B2bRules::Chaining);
+    }
+
+    #[test]
+    fn season2_container_without_a_replay_is_unsupported() {
+        let replay = json!({"replay": {"leaderboard": []}}).to_string();
+        assert!(matches!(
+            decode_replay(&replay),
+            Err(DecodeError::Unsupported)
+        ));
+    }
+}
@@ -1,9 +1,13 @@
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
+use crate::ruleset::Ruleset;
+
 pub type Board = Vec<MinoType>;
 
-#[derive(Debug, Serialize, Deserialize)]
+pub const CLEAR_TYPES: usize = 21;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlacementStats {
     pub shape: MinoType,
     #[serde(rename = "linesCleared")]
@@ -23,10 +27,47 @@ pub struct PlacementStats {
     pub frame_delay: f64,
     #[serde(rename = "attackRecieved")]
     pub attack_received: Vec<usize>,
+    #[serde(default, rename = "garbageColumns")]
+    pub garbage_columns: Vec<Option<usize>>, //hole column of each attack received, none if the game picks it
     #[serde(rename = "attackTanked")]
     pub attack_tanked: Vec<usize>,
     pub board: Board,
     pub queue: Vec<MinoType>,
+    #[serde(default)]
+    pub ruleset: Ruleset,
+}
+
+///a t that left `board` behind without clearing, sending or receiving anything, tests set what they need on top
+#[cfg(test)]
+pub fn placement_on(board: Board) -> PlacementStats {
+    PlacementStats {
+        shape: MinoType::T,
+        lines_cleared: 0,
+        garbage_cleared: 0,
+        keypresses: 0,
+        attack: Vec::new(),
+        clear_type: ClearType::None,
+        combo: 0,
+        btb_chain: 0,
+        btb_clear: false,
+        frame_delay: 10.0,
+        attack_received: Vec::new(),
+        garbage_columns: Vec::new(),
+        attack_tanked: Vec::new(),
+        board,
+        queue: Vec::new(),
+        ruleset: Ruleset::default(),
+    }
+}
+
+///which pieces can score spins in the game a placement came from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SpinRules {
+    #[default]
+    TSpins,
+    AllMini, //every other piece spins as a mini and keeps b2b, tetrio's all-mini
+    None,    //no piece scores spins, t included
 }
 
 #[derive(Hash, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -64,6 +105,16 @@ pub enum ClearType {
     Penta,
     #[serde(rename = "PERFECT_CLEAR")]
     PerfectClear,
+    #[serde(rename = "ALLSPIN")]
+    Allspin,
+    #[serde(rename = "ALLSPIN_SINGLE")]
+    AllspinSingle,
+    #[serde(rename = "ALLSPIN_DOUBLE")]
+    AllspinDouble,
+    #[serde(rename = "ALLSPIN_TRIPLE")]
+    AllspinTriple,
+    #[serde(rename = "ALLSPIN_QUAD")]
+    AllspinQuad,
 }
 
 impl ClearType {
@@ -77,6 +128,10 @@ impl ClearType {
             || self == &Self::TspinSingle
             || self == &Self::TspinMiniSingle
             || self == &Self::TspinMiniDouble
+            || self == &Self::AllspinSingle
+            || self == &Self::AllspinDouble
+            || self == &Self::AllspinTriple
+            || self == &Self::AllspinQuad
     }
 }
 
@@ -114,6 +169,11 @@ impl std::convert::TryFrom<u8> for ClearType {
             13 => Ok(ClearType::TspinPenta),
             14 => Ok(ClearType::Penta),
             15 => Ok(ClearType::PerfectClear),
+            16 => Ok(ClearType::Allspin),
+            17 => Ok(ClearType::AllspinSingle),
+            18 => Ok(ClearType::AllspinDouble),
+            19 => Ok(ClearType::AllspinTriple),
+            20 => Ok(ClearType::AllspinQuad),
             _ => Err(OutOfBoundsError(value)),
         }
     }
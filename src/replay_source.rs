@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+
+use crate::files::{is_file_handle, temp_path};
+use crate::placement_stats::CumulativePlacementStats;
+use crate::replay_decoder::{decode_replay, DecodeError};
+use crate::replay_response::PlacementStats;
+
+///placement sequences per player, one entry per game, none for games that were corrupt
+pub type PlayerGames = Vec<(String, Vec<Option<Vec<PlacementStats>>>)>;
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Unsupported,
+    Unparsable,
+    Unmunchable,
+    Corrupt,
+    Connection,
+    HashMismatch,
+}
+
+impl Error for ReplayError {}
+
+impl ReplayError {
+    ///stable identifier for api clients, unlike the display message
+    pub fn code(&self) -> &'static str {
+        match self {
+            ReplayError::Unsupported => "replay_unsupported",
+            ReplayError::Unparsable => "replay_unparsable",
+            ReplayError::Unmunchable => "replay_unmunchable",
+            ReplayError::Corrupt => "replay_corrupt",
+            ReplayError::Connection => "parser_connection",
+            ReplayError::HashMismatch => "replay_hash_mismatch",
+        }
+    }
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReplayError::Unsupported => write!(f, "The replay's version is unsupported."),
+            ReplayError::Unparsable => write!(
+                f,
+                "The replay was unable to be identified as a valid replay."
+            ),
+            ReplayError::Unmunchable => write!(
+                f,
+                "The replay's data was unable to be processed into stats."
+            ),
+            ReplayError::Corrupt => write!(
+                f,
+                "The replay is corrupt, no data was able to be processed."
+            ),
+            ReplayError::Connection => {
+                write!(f, "A connection error occurred with the replay parser.")
+            }
+            ReplayError::HashMismatch => {
+                write!(
+                    f,
+                    "The replay's content doesn't match the hash it was sent with."
+                )
+            }
+        }
+    }
+}
+
+impl From<DecodeError> for ReplayError {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::Unsupported => ReplayError::Unsupported,
+            DecodeError::Unparsable => ReplayError::Unparsable,
+        }
+    }
+}
+
+///removes wrapper characters around tcp streams
+pub fn sanitize_string(s: &str) -> String {
+    s.trim_start_matches('\u{feff}')
+        .trim_end_matches('\n')
+        .trim_end_matches('\r')
+        .to_string()
+}
+
+///blake3 of a replay with formatting stripped, the cache key of uploaded replays. clients hashing
+///their own uploads have to produce the same bytes, see `canonical_json`. text that isn't json is
+///hashed as is, bar a leading bom and surrounding whitespace
+pub fn content_hash(replay: &str) -> String {
+    let replay = sanitize_string(replay);
+    let normalized = match serde_json::from_str::<serde_json::Value>(&replay) {
+        Ok(value) => {
+            let mut canonical = String::new();
+            canonical_json(&value, &mut canonical);
+            canonical
+        }
+        Err(_) => replay.trim().to_string(),
+    };
+    blake3::hash(normalized.as_bytes()).to_hex().to_string()
+}
+
+///json written one way whatever the input looked like: no whitespace, object keys sorted by their
+///utf-8 bytes, strings escaped as serde_json does. whole numbers within 2^53 are written as integers
+///so 1, 1.0 and 1e0 agree, other numbers in their shortest form that reads back the same
+pub fn canonical_json(value: &serde_json::Value, out: &mut String) {
+    use serde_json::Value;
+    match value {
+        Value::Null | Value::Bool(_) | Value::String(_) => out.push_str(&value.to_string()),
+        Value::Number(number) => match number.as_f64() {
+            Some(float)
+                if number.is_f64() && float.fract() == 0.0 && float.abs() <= 2f64.powi(53) =>
+            {
+                out.push_str(&(float as i64).to_string())
+            }
+            _ => out.push_str(&number.to_string()),
+        },
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonical_json(item, out);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::from(key.as_str()).to_string());
+                out.push(':');
+                canonical_json(item, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+///somewhere placement streams can be pulled from
+pub trait ReplaySource: Send + Sync {
+    ///placements of every player in the replay whose lowercase name is in filtered, empty filter takes everyone.
+    ///what `replay` is depends on the source: raw replay text for parsers, a handle for stored placements
+    fn placements(&self, replay: &str, filtered: &[String]) -> Result<PlayerGames, ReplayError>;
+}
+
+///whether a player passes a lowercase name filter, an empty filter takes everyone
+pub fn is_requested(name: &str, filtered: &[String]) -> bool {
+    filtered.is_empty() || filtered.contains(&name.to_lowercase())
+}
+
+///decodes replays in process
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NativeDecoder;
+
+impl ReplaySource for NativeDecoder {
+    fn placements(&self, replay: &str, filtered: &[String]) -> Result<PlayerGames, ReplayError> {
+        Ok(decode_replay(replay)?
+            .into_iter()
+            .filter(|player| is_requested(&player.name, filtered))
+            .map(|player| (player.name, player.games))
+            .collect())
+    }
+}
+
+///client for the line based protocol of the modded csdotnet replay parser
+#[derive(Debug, Clone)]
+pub struct ParserClient {
+    addr: String,
+}
+
+impl ParserClient {
+    pub fn new(port: usize) -> Self {
+        Self {
+            addr: format!("127.0.0.1:{}", port),
+        }
+    }
+    ///port read from TETRIO_PARSER_PORT, default 8080
+    pub fn from_env() -> Self {
+        let port: usize = std::env::var("TETRIO_PARSER_PORT")
+            .ok()
+            .and_then(|s: String| s.parse().ok())
+            .unwrap_or(8080);
+        Self::new(port)
+    }
+}
+
+fn write_line(writer: &mut impl Write, line: &str) -> Result<(), ReplayError> {
+    writer
+        .write_all(line.as_bytes())
+        .or(Err(ReplayError::Connection))?;
+    writer.write_all(b"\n").or(Err(ReplayError::Connection))?;
+    writer.flush().or(Err(ReplayError::Connection))?;
+    Ok(())
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<String, ReplayError> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .or(Err(ReplayError::Unparsable))?;
+    Ok(sanitize_string(&line))
+}
+
+impl ReplaySource for ParserClient {
+    fn placements(&self, replay: &str, filtered: &[String]) -> Result<PlayerGames, ReplayError> {
+        let stream = TcpStream::connect(&self.addr).or(Err(ReplayError::Connection))?;
+        let mut reader = BufReader::new(stream.try_clone().or(Err(ReplayError::Connection))?);
+        let mut writer = BufWriter::new(stream);
+
+        write_line(&mut writer, &sanitize_string(replay))?;
+        //write replay string
+
+        let supported: bool = read_line(&mut reader)?
+            .parse()
+            .or(Err(ReplayError::Unparsable))?;
+        if !supported {
+            return Err(ReplayError::Unsupported);
+        }
+        //ask parser if version is supported or not
+
+        let names: Vec<_> = read_line(&mut reader)?
+            .split(' ')
+            .filter(|name| is_requested(name, filtered))
+            .map(|s| s.to_string())
+            .collect();
+        //get names in replay, filtered with case insensitivity
+
+        let num_games: usize = read_line(&mut reader)?
+            .parse()
+            .or(Err(ReplayError::Unparsable))?;
+        //get number of games of replay
+
+        write_line(&mut writer, &names.len().to_string())?;
+        //write number of names to get stats for
+
+        let mut players = Vec::new();
+        for name in names {
+            write_line(&mut writer, &name)?; //request stats for [name] from parser
+
+            let mut games = Vec::new();
+            for _ in 0..num_games {
+                let game = read_line(&mut reader)?; //parse individual placement sequences for each game
+                if game == "CORRUPT" {
+                    games.push(None);
+                    continue;
+                }
+                let placements: Vec<PlacementStats> =
+                    serde_json::from_str(&game).or(Err(ReplayError::Unmunchable))?;
+                games.push(Some(placements));
+            }
+            players.push((name, games));
+        }
+        Ok(players)
+    }
+}
+
+///pre extracted placements, one `<handle>.json` per replay mapping player names to their games
+#[derive(Debug, Clone)]
+pub struct PlacementDirectory {
+    path: PathBuf,
+}
+
+impl PlacementDirectory {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+    ///writes placements so they can be read back under the same handle
+    pub fn store(&self, handle: &str, players: &PlayerGames) -> std::io::Result<()> {
+        if !is_file_handle(handle) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{handle:?} can't name an archive file"),
+            ));
+        }
+        let players: HashMap<_, _> = players.iter().map(|(name, games)| (name, games)).collect();
+        let file = File::create(self.path.join(format!("{handle}.json")))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &players)?;
+        writer.flush()
+    }
+}
+
+impl ReplaySource for PlacementDirectory {
+    fn placements(&self, replay: &str, filtered: &[String]) -> Result<PlayerGames, ReplayError> {
+        if !is_file_handle(replay) {
+            return Err(ReplayError::Unparsable);
+        }
+        let file = File::open(self.path.join(format!("{replay}.json")))
+            .or(Err(ReplayError::Unparsable))?;
+        let players: HashMap<String, Vec<Option<Vec<PlacementStats>>>> =
+            serde_json::from_reader(BufReader::new(file)).or(Err(ReplayError::Unmunchable))?;
+        Ok(players
+            .into_iter()
+            .filter(|(name, _)| is_requested(name, filtered))
+            .collect())
+    }
+}
+
+///where processed replays' placements are archived when ARCHIVE_PLACEMENTS is set
+pub const ARCHIVE_PATH: &str = ".placementArchive";
+
+///zstd compressed placements, one `<handle>.json.zst` per replay mapping player names to their games.
+///keeps every game of every player, so stats can be recomputed offline after they change
+#[derive(Debug, Clone)]
+pub struct PlacementArchive {
+    path: PathBuf,
+}
+
+impl PlacementArchive {
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+    ///written aside and renamed over, so a reader never sees a partial archive
+    pub fn store(&self, handle: &str, players: &PlayerGames) -> std::io::Result<()> {
+        if !is_file_handle(handle) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{handle:?} can't name an archive file"),
+            ));
+        }
+        let players: HashMap<_, _> = players.iter().map(|(name, games)| (name, games)).collect();
+        let temp_path = temp_path(&self.path, &format!("{handle}.json.zst"));
+        let mut encoder = zstd::Encoder::new(BufWriter::new(File::create(&temp_path)?), 0)?;
+        serde_json::to_writer(&mut encoder, &players)?;
+        encoder.finish()?.flush()?;
+        std::fs::rename(temp_path, self.path.join(format!("{handle}.json.zst")))
+    }
+    ///handles of every archived replay
+    pub fn handles(&self) -> std::io::Result<Vec<String>> {
+        Ok(std::fs::read_dir(&self.path)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                name.strip_suffix(".json.zst")
+                    .filter(|handle| !handle.starts_with('.'))
+                    .map(|handle| handle.to_string())
+            })
+            .collect())
+    }
+}
+
+impl ReplaySource for PlacementArchive {
+    fn placements(&self, replay: &str, filtered: &[String]) -> Result<PlayerGames, ReplayError> {
+        if !is_file_handle(replay) {
+            return Err(ReplayError::Unparsable);
+        }
+        let file = File::open(self.path.join(format!("{replay}.json.zst")))
+            .or(Err(ReplayError::Unparsable))?;
+        let decoder = zstd::Decoder::new(file).or(Err(ReplayError::Corrupt))?;
+        let players: HashMap<String, Vec<Option<Vec<PlacementStats>>>> =
+            serde_json::from_reader(BufReader::new(decoder)).or(Err(ReplayError::Unmunchable))?;
+        Ok(players
+            .into_iter()
+            .filter(|(name, _)| is_requested(name, filtered))
+            .collect())
+    }
+}
+
+///placements held in memory, keyed by handle
+#[derive(Debug, Default, Clone)]
+pub struct FixtureSource {
+    replays: HashMap<String, PlayerGames>,
+}
+
+impl FixtureSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_replay(mut self, handle: &str, players: PlayerGames) -> Self {
+        self.replays.insert(handle.to_string(), players);
+        self
+    }
+}
+
+impl ReplaySource for FixtureSource {
+    fn placements(&self, replay: &str, filtered: &[String]) -> Result<PlayerGames, ReplayError> {
+        let players = self.replays.get(replay).ok_or(ReplayError::Unparsable)?;
+        Ok(players
+            .iter()
+            .filter(|(name, _)| is_requested(name, filtered))
+            .cloned()
+            .collect())
+    }
+}
+
+///turns every game into stats on its own thread, keeping each game with its round index
+pub fn munch_games(
+    players: PlayerGames,
+) -> Result<Vec<(String, Vec<(usize, CumulativePlacementStats)>)>, ReplayError> {
+    let mut fully_corrupt = true;
+    let mut stats = Vec::new();
+
+    for (name, games) in players {
+        let handles: Vec<_> = games
+            .into_iter()
+            .enumerate()
+            .filter_map(|(round, placements)| placements.map(|placements| (round, placements)))
+            .map(|(round, placements)| {
+                (
+                    round,
+                    thread::spawn(move || CumulativePlacementStats::from(placements.as_slice())),
+                )
+            })
+            .collect();
+        //this from operation is heavy, one thread per game
+
+        let mut game_stats = Vec::new();
+        for (round, handle) in handles {
+            fully_corrupt = false;
+            game_stats.push((round, handle.join().or(Err(ReplayError::Unmunchable))?));
+        }
+        stats.push((name, game_stats));
+    }
+
+    if fully_corrupt {
+        return Err(ReplayError::Corrupt);
+    } //no data received? return corrupt
+    Ok(stats)
+}
+
+///turns every game into stats on its own thread and merges them per player
+pub fn munch_players(
+    players: PlayerGames,
+) -> Result<Vec<(String, CumulativePlacementStats)>, ReplayError> {
+    Ok(munch_games(players)?
+        .into_iter()
+        .map(|(name, games)| {
+            let mut cumulative_stats = CumulativePlacementStats::default();
+            for (_, game_stats) in games {
+                cumulative_stats.absorb(game_stats);
+            }
+            (name, cumulative_stats)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_hash_ignores_formatting() {
+        let compact = r#"{"b":[1,2.5,"x"],"a":{"d":null,"c":true}}"#;
+        let spread =
+            "\u{feff} {\n  \"a\": {\"c\": true, \"d\": null},\n  \"b\": [1.0, 25e-1, \"x\"]\n}\r\n";
+        assert_eq!(content_hash(compact), content_hash(spread));
+        assert_ne!(
+            content_hash(compact),
+            content_hash(r#"{"b":[1,2.5,"y"],"a":{}}"#)
+        );
+    }
+
+    #[test]
+    fn canonical_json_is_specified() {
+        let value: serde_json::Value =
+            serde_json::from_str(r#"{"z":1e0,"é":"A\n","a":[-0.5,3.0,18446744073709551615]}"#)
+                .unwrap();
+        let mut canonical = String::new();
+        canonical_json(&value, &mut canonical);
+        assert_eq!(
+            canonical,
+            r#"{"a":[-0.5,3,18446744073709551615],"z":1,"é":"A\n"}"#
+        );
+    }
+}
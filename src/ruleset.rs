@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+use crate::engine::{BOARD_HEIGHT, BOARD_WIDTH};
+use crate::replay_response::SpinRules;
+
+///how consecutive clears add to attack
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ComboTable {
+    Multiplier, //tetrio, attack grows by a quarter per combo and no attack clears get a log bonus
+    Jstris,
+    Tetris99,
+}
+
+const JSTRIS_COMBO: [usize; 13] = [0, 0, 1, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5];
+const TETRIS99_COMBO: [usize; 11] = [0, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5];
+
+impl ComboTable {
+    ///attack after the combo, combo being the number of clears before this one
+    pub fn apply(&self, attack: f64, combo: usize) -> f64 {
+        let table: &[usize] = match self {
+            ComboTable::Multiplier => {
+                if attack == 0.0 && combo >= 2 {
+                    return (1.0 + 1.25 * combo as f64).ln();
+                }
+                return attack * (1.0 + 0.25 * combo as f64);
+            }
+            ComboTable::Jstris => &JSTRIS_COMBO,
+            ComboTable::Tetris99 => &TETRIS99_COMBO,
+        };
+        attack + table[combo.min(table.len() - 1)] as f64
+    }
+}
+
+///what keeping b2b up is worth
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum B2bRules {
+    Chaining, //tetrio season 1, the bonus grows with the chain
+    Flat,     //a single extra line
+    Surge,    //tetrio season 2, a flat line while it lasts and charged garbage once it breaks
+}
+
+///everything about a game's rules that changes attack, spins and the board
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Ruleset {
+    pub combo_table: ComboTable,
+    pub b2b: B2bRules,
+    pub pc_bonus: usize,
+    pub garbage_multiplier: f64,
+    pub spin_rules: SpinRules,
+    pub width: usize,
+    pub height: usize, //visible rows, pieces spawn right above them
+}
+
+impl Ruleset {
+    pub fn tetrio_s1() -> Self {
+        Self {
+            combo_table: ComboTable::Multiplier,
+            b2b: B2bRules::Chaining,
+            pc_bonus: 10,
+            garbage_multiplier: 1.0,
+            spin_rules: SpinRules::TSpins,
+            width: 10,
+            height: 20,
+        }
+    }
+    pub fn tetrio_s2() -> Self {
+        Self {
+            b2b: B2bRules::Surge,
+            pc_bonus: 5,
+            spin_rules: SpinRules::AllMini,
+            ..Self::tetrio_s1()
+        }
+    }
+    pub fn guideline() -> Self {
+        Self {
+            combo_table: ComboTable::Jstris,
+            b2b: B2bRules::Flat,
+            ..Self::tetrio_s1()
+        }
+    }
+    pub fn tetris99() -> Self {
+        Self {
+            combo_table: ComboTable::Tetris99,
+            ..Self::guideline()
+        }
+    }
+    ///fingerprint of every preset, it changes whenever one of their numbers does
+    pub fn presets_id() -> String {
+        let presets = format!(
+            "{:?}",
+            [
+                Self::tetrio_s1(),
+                Self::tetrio_s2(),
+                Self::guideline(),
+                Self::tetris99()
+            ]
+        );
+        let hash = presets.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        //fnv-1a, unlike std's hasher it's the same on every build
+        format!("{:016x}", hash)
+    }
+    ///boards are stored `BOARD_WIDTH` wide, so other widths can't be played, and pieces need
+    ///a few rows above `height` to spawn in
+    pub fn fits_board(&self) -> bool {
+        self.width == BOARD_WIDTH && (4..=BOARD_HEIGHT - 4).contains(&self.height)
+    }
+    ///tetrio season 1 is covered exactly by osk's attack table
+    pub fn uses_attack_table(&self) -> bool {
+        self.combo_table == ComboTable::Multiplier && self.b2b == B2bRules::Chaining
+    }
+}
+
+impl Default for Ruleset {
+    fn default() -> Self {
+        Self::tetrio_s1()
+    }
+}
@@ -1,8 +1,21 @@
-use crate::replay_response::{Board, MinoType};
-use std::{collections::VecDeque, fmt::Display};
+use crate::replay_response::{Board, ClearType, MinoType, SpinRules, CLEAR_TYPES};
+use crate::ruleset::Ruleset;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
 
-use crate::attack::get_indexed_attack;
+use crate::attack::{get_clear_attack, get_surge_attack};
+use crate::board_analyzer::{from_shape, to_board64, to_shape};
+use crate::engine::{classify_clear, Spin, BOARD_WIDTH};
+use crate::finesse::placement_cells;
 use bitris::prelude::*;
+use serde::{Deserialize, Serialize};
+
+///pieces searched past the hold
+pub const PREVIEW_PIECES: usize = 7;
 
 ///parse replay response types into a bitris node and queue
 fn parse_replay_args(
@@ -10,32 +23,14 @@ fn parse_replay_args(
     btb: usize,
     combo: usize,
     queue: &[MinoType],
+    ruleset: &Ruleset,
 ) -> (Node, VecDeque<Shape>) {
-    let mut board64 = Board64::blank();
-    for y in 0..40 {
-        for x in 0..10 {
-            if board[(39 - y) * 10 + x] != MinoType::Empty {
-                board64.set_at(Location {
-                    x: x as i32,
-                    y: y as i32,
-                });
-            }
-        }
-    }
-    let mut vec_queue = VecDeque::new();
-    for &p in queue.iter().take(8) {
-        use Shape::*;
-        vec_queue.push_back(match p {
-            MinoType::Z => Z,
-            MinoType::L => L,
-            MinoType::O => O,
-            MinoType::S => S,
-            MinoType::I => I,
-            MinoType::J => J,
-            MinoType::T => T,
-            _ => continue,
-        })
-    }
+    let board64 = to_board64(board);
+    let mut vec_queue: VecDeque<_> = queue
+        .iter()
+        .take(PREVIEW_PIECES + 1)
+        .filter_map(|&p| to_shape(p))
+        .collect();
     let hold = vec_queue.pop_front().unwrap();
     let node = Node {
         board: board64,
@@ -43,39 +38,324 @@ fn parse_replay_args(
         btb,
         combo,
         attack: 0,
+        ruleset: *ruleset,
     };
     (node, vec_queue)
 }
 
 ///dfs to get atk and def
-pub fn solve_state(board: &Board, btb: usize, combo: usize, queue: &[MinoType]) -> (usize, usize) {
-    let (node, mut queue) = parse_replay_args(board, btb, combo, queue);
-    dfs(node, &mut queue)
+pub fn solve_state(
+    board: &Board,
+    btb: usize,
+    combo: usize,
+    queue: &[MinoType],
+    garbage: &[PendingGarbage],
+    ruleset: &Ruleset,
+) -> (usize, usize) {
+    solve_state_with_budget(
+        board,
+        btb,
+        combo,
+        queue,
+        garbage,
+        ruleset,
+        SolverBudget::from_env(),
+    )
+}
+
+///dfs to get atk and def, giving up on lines it hasn't reached once the budget runs out.
+///the same as a full search while the budget lasts, after that attack is only the best line reached
+pub fn solve_state_with_budget(
+    board: &Board,
+    btb: usize,
+    combo: usize,
+    queue: &[MinoType],
+    garbage: &[PendingGarbage],
+    ruleset: &Ruleset,
+    budget: SolverBudget,
+) -> (usize, usize) {
+    let (node, queue) = parse_replay_args(board, btb, combo, queue, ruleset);
+    let queue: Vec<_> = queue.into();
+    let mut search = Search::new(&node, &queue, garbage, budget, false);
+    search.dfs(node, 0);
+    search.best
+}
+
+///atk and def along with the line reaching that attack, for showing a single board.
+///carrying lines through the search costs time, so stats stick to `solve_state`
+pub fn solve_line(
+    board: &Board,
+    btb: usize,
+    combo: usize,
+    queue: &[MinoType],
+    garbage: &[PendingGarbage],
+    ruleset: &Ruleset,
+) -> SolverLine {
+    let (node, queue) = parse_replay_args(board, btb, combo, queue, ruleset);
+    let queue: Vec<_> = queue.into();
+    let mut search = Search::new(&node, &queue, garbage, SolverBudget::from_env(), true);
+    let outcome = search.dfs(node.clone(), 0);
+
+    let mut board = node.board;
+    let steps = outcome
+        .line
+        .into_iter()
+        .map(|step| {
+            let cells = placement_cells(&step.placement);
+            step.placement.place_on_and_clear_lines(&mut board);
+            SolverStep {
+                shape: from_shape(step.placement.piece.shape),
+                cells,
+                hold: step.hold,
+                clear_type: step.clear_type,
+                attack: step.attack,
+                board: render_board(&board),
+            }
+        })
+        .collect();
+    SolverLine {
+        attack: search.best.0,
+        defence: search.best.1,
+        steps,
+    }
+}
+
+///garbage queued against the player that hasn't been cancelled or tanked yet
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PendingGarbage {
+    pub amount: usize,
+    pub column: Option<usize>, //hole column, none if the game picks it when it's tanked
+}
+
+///pushes what's left of the pending garbage after cancelling up from the bottom, oldest first.
+///unknown hole columns reuse the one before
+fn tank_garbage(board: &mut Board64, garbage: &[PendingGarbage], cancelled: usize) {
+    let mut skipped = cancelled;
+    let mut column = 0;
+    for chunk in garbage {
+        column = chunk.column.unwrap_or(column);
+        let lines = chunk.amount.saturating_sub(skipped).min(40);
+        skipped = skipped.saturating_sub(chunk.amount);
+        if lines == 0 {
+            continue;
+        }
+        for (x, col) in board.cols.iter_mut().enumerate() {
+            *col <<= lines;
+            if x != column {
+                *col |= (1 << lines) - 1;
+            }
+        }
+    }
+}
+
+///the best line the solver found, for showing what a board could have sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SolverLine {
+    pub attack: usize,
+    pub defence: usize,
+    pub steps: Vec<SolverStep>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SolverStep {
+    pub shape: MinoType,
+    pub cells: [(i32, i32); 4], //where the piece goes on the board before this step, y up
+    pub hold: bool,             //whether the piece came out of hold
+    pub clear_type: ClearType,
+    pub attack: usize,
+    pub board: String, //the board after this step
+}
+
+///rows from the highest filled one down, `#` filled and `.` empty
+pub fn render_board(board: &Board64) -> String {
+    let filled = |x: usize, y: usize| {
+        board.is_occupied_at(Location {
+            x: x as i32,
+            y: y as i32,
+        })
+    };
+    let Some(top) = (0..64)
+        .rev()
+        .find(|&y| (0..BOARD_WIDTH).any(|x| filled(x, y)))
+    else {
+        return String::new();
+    };
+    (0..=top)
+        .rev()
+        .map(|y| {
+            (0..BOARD_WIDTH)
+                .map(|x| if filled(x, y) { '#' } else { '.' })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+static SOLVER_POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum SolverPoolError {
+    AlreadyBuilt, //a solve or an earlier call built it first, its thread count stays
+    Build(rayon::ThreadPoolBuildError),
 }
 
-//we do tspin check with immobile, hopefully it is sufficient
-fn is_immobile(board: &Board64, placement: &BlPlacement) -> bool {
-    let north = placement + Offset { dx: 0, dy: 1 };
-    if north.is_in_free_space(board) {
-        return false;
+impl std::error::Error for SolverPoolError {}
+
+impl Display for SolverPoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SolverPoolError::AlreadyBuilt => f.write_str("solver pool is already built"),
+            SolverPoolError::Build(e) => write!(f, "solver pool could not be built: {}", e),
+        }
     }
+}
 
-    let south = placement + Offset { dx: 0, dy: -1 };
-    if south.is_in_free_space(board) {
-        return false;
+///caps the threads every solve shares, 0 for one per core. only works before the pool is first used
+pub fn init_solver_pool(threads: usize) -> Result<(), SolverPoolError> {
+    if SOLVER_POOL.get().is_some() {
+        return Err(SolverPoolError::AlreadyBuilt);
     }
+    let pool = build_solver_pool(threads).map_err(SolverPoolError::Build)?;
+    SOLVER_POOL
+        .set(pool)
+        .map_err(|_| SolverPoolError::AlreadyBuilt)
+}
+
+///work stealing pool the solver runs on, shared so one huge replay can't take more than the cap
+pub fn solver_pool() -> &'static rayon::ThreadPool {
+    SOLVER_POOL.get_or_init(|| build_solver_pool(0).expect("unable to build the solver pool"))
+}
+
+fn build_solver_pool(threads: usize) -> Result<rayon::ThreadPool, rayon::ThreadPoolBuildError> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("solver-{}", i))
+        .build()
+}
+
+///how much of the tree a single solve may search, unlimited by default.
+///results are exact only for solves that finish within it
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SolverBudget {
+    pub max_nodes: Option<usize>,
+    pub max_time: Option<Duration>,
+}
 
-    let east = placement + Offset { dx: 1, dy: 0 };
-    if east.is_in_free_space(board) {
-        return false;
+impl SolverBudget {
+    ///read once from SOLVER_MAX_NODES and SOLVER_MAX_MILLIS
+    pub fn from_env() -> Self {
+        static BUDGET: OnceLock<SolverBudget> = OnceLock::new();
+        *BUDGET.get_or_init(|| SolverBudget {
+            max_nodes: std::env::var("SOLVER_MAX_NODES")
+                .ok()
+                .and_then(|nodes| nodes.parse().ok()),
+            max_time: std::env::var("SOLVER_MAX_MILLIS")
+                .ok()
+                .and_then(|millis| millis.parse().ok())
+                .map(Duration::from_millis),
+        })
     }
+}
+
+///walls and the floor count as filled for the corner checks
+fn is_filled(board: &Board64, x: i32, y: i32) -> bool {
+    x < 0 || x >= BOARD_WIDTH as i32 || y < 0 || board.is_occupied_at(Location { x, y })
+}
+
+///tetrio's t-spin rules: three of the four corners around the center filled, and a mini
+///unless both corners the t points at are filled or the last kick was the final srs one
+fn t_spin(board: &Board64, placement: &BlPlacement, last_kick: usize) -> Spin {
+    let center = placement.to_cc_placement();
+    let (cx, cy) = (center.position.cx, center.position.cy);
+    let corners =
+        [(-1, 1), (1, 1), (1, -1), (-1, -1)].map(|(dx, dy)| is_filled(board, cx + dx, cy + dy));
+    //clockwise from the top left, so the t's front corners are its orientation and the next one
 
-    let west = placement + Offset { dx: -1, dy: 0 };
-    if west.is_in_free_space(board) {
-        return false;
+    if corners.iter().filter(|&&filled| filled).count() < 3 {
+        return Spin::None;
+    }
+    let front = match center.piece.orientation {
+        Orientation::North => 0,
+        Orientation::East => 1,
+        Orientation::South => 2,
+        Orientation::West => 3,
+    };
+    if (corners[front] && corners[(front + 1) % 4]) || last_kick == 4 {
+        Spin::Full
+    } else {
+        Spin::Mini
     }
+}
 
-    true
+///the placement after a srs rotation and the index of the kick that made it fit
+fn rotate(
+    board: &Board64,
+    placement: &BlPlacement,
+    rotation: Rotation,
+) -> Option<(BlPlacement, usize)> {
+    let from = placement.to_cc_placement();
+    let piece = Piece::new(from.piece.shape, from.piece.orientation.rotate(rotation));
+    SrsKickTable
+        .iter_kicks(from.piece, rotation)
+        .enumerate()
+        .map(|(i, kick)| {
+            let position = cc(
+                from.position.cx + kick.offset.dx,
+                from.position.cy + kick.offset.dy,
+            );
+            (piece.with(position).to_bl_placement(), i)
+        })
+        .find(|(rotated, _)| rotated.is_in_free_space(board))
+}
+
+///all-mini spins, a piece rotated into a spot it can't move out of
+fn immobile_spin(board: &Board64, placement: &BlPlacement) -> Spin {
+    let immobile = [(-1, 0), (1, 0), (0, 1)]
+        .iter()
+        .all(|&(dx, dy)| !(placement + Offset { dx, dy }).is_in_free_space(board));
+    if immobile {
+        Spin::Mini
+    } else {
+        Spin::None
+    }
+}
+
+///every landing spot reachable from spawn and the best spin it can score there.
+///bitris' move generation forgets how a piece got somewhere, so this searches keeping the last kick
+fn generate_spin_moves(board: &Board64, spawn: BlPlacement) -> Vec<(BlPlacement, Spin)> {
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    let mut landed: HashMap<BlPlacement, Spin> = HashMap::new();
+    visited.insert((spawn, None));
+    queue.push_back((spawn, None));
+
+    while let Some((placement, last_kick)) = queue.pop_front() {
+        if !(placement + Offset { dx: 0, dy: -1 }).is_in_free_space(board) {
+            let spin = match last_kick {
+                None => Spin::None,
+                Some(kick) if placement.piece.shape == Shape::T => t_spin(board, &placement, kick),
+                Some(_) => immobile_spin(board, &placement),
+            };
+            let best = landed.entry(placement).or_insert(spin);
+            *best = (*best).max(spin);
+        }
+
+        for (dx, dy) in [(-1, 0), (1, 0), (0, -1)] {
+            let moved = placement + Offset { dx, dy };
+            if moved.is_in_free_space(board) && visited.insert((moved, None)) {
+                queue.push_back((moved, None));
+            }
+        }
+        for rotation in [Rotation::Cw, Rotation::Ccw] {
+            if let Some((rotated, kick)) = rotate(board, &placement, rotation) {
+                if visited.insert((rotated, Some(kick))) {
+                    queue.push_back((rotated, Some(kick)));
+                }
+            }
+        }
+    }
+    landed.into_iter().collect()
 }
 
 #[derive(Clone)]
@@ -85,6 +365,7 @@ struct Node {
     btb: usize,
     combo: usize,
     attack: usize,
+    ruleset: Ruleset,
 }
 
 impl Display for Node {
@@ -98,10 +379,16 @@ impl Display for Node {
 }
 
 impl Node {
+    fn spawn_position(&self) -> CcPosition {
+        cc(
+            self.ruleset.width as i32 / 2 - 1,
+            self.ruleset.height as i32 + 1,
+        )
+    }
     fn get_fall_height(&self, shape: Shape) -> usize {
         let mut defence = 0;
         let mut spawn = Piece::new(shape, Orientation::North)
-            .with(cc(4, 21))
+            .with(self.spawn_position())
             .to_bl_placement();
         while spawn.is_in_free_space(&self.board) {
             defence += 1;
@@ -109,55 +396,64 @@ impl Node {
         }
         defence
     }
-    fn get_children(&self, shape: Shape, next_hold: Shape) -> Vec<Self> {
+    fn get_children(&self, shape: Shape, next_hold: Shape) -> Vec<(Self, Step)> {
         let spawn = Piece::new(shape, Orientation::North)
-            .with(cc(4, 21))
+            .with(self.spawn_position())
             .to_bl_placement();
         if !spawn.is_in_free_space(&self.board) {
             return Vec::new();
         }
 
-        let move_rules = MoveRules::default();
-
-        let minimized_moves = move_rules.generate_minimized_moves(self.board, spawn);
+        let spins = match self.ruleset.spin_rules {
+            SpinRules::TSpins => shape == Shape::T,
+            SpinRules::AllMini => true,
+            SpinRules::None => false,
+        };
+        let moves = if spins {
+            generate_spin_moves(&self.board, spawn)
+        } else {
+            MoveRules::default()
+                .generate_minimized_moves(self.board, spawn)
+                .into_iter()
+                .map(|placement| (placement, Spin::None))
+                .collect()
+        };
 
-        minimized_moves
+        moves
             .into_iter()
-            .filter_map(|placement| {
+            .filter_map(|(placement, spin)| {
                 let mut new_node = self.clone();
                 let lines_cleared = placement
                     .place_on_and_clear_lines(&mut new_node.board)
                     .unwrap_or(Lines::blank())
                     .count();
                 if lines_cleared > 0 {
-                    let mut clear_type = lines_cleared as usize;
-                    let mut is_btb = false;
-                    if shape == Shape::T && is_immobile(&self.board, &placement) {
-                        if lines_cleared == 1 {
-                            clear_type = 5;
-                        } else if lines_cleared == 2 {
-                            clear_type = 7;
-                        } else if lines_cleared == 3 {
-                            clear_type = 8;
-                        }
-                        is_btb = true;
-                    }
-                    if lines_cleared == 4 {
-                        is_btb = true;
-                    }
+                    let clear_type =
+                        classify_clear(lines_cleared as usize, spin, shape == Shape::T);
+                    let is_btb = spin != Spin::None || lines_cleared == 4;
                     if is_btb {
                         new_node.btb += 1;
                     } else {
+                        new_node.attack +=
+                            get_surge_attack(&self.ruleset, self.btb.saturating_sub(1));
                         new_node.btb = 0;
                     }
-                    let mut atk = get_indexed_attack(clear_type, self.combo, self.btb);
-                    if new_node.board.is_empty() {
-                        atk += 10;
-                    }
-                    new_node.attack += atk;
+                    new_node.attack += get_clear_attack(
+                        &self.ruleset,
+                        clear_type,
+                        self.combo,
+                        self.btb,
+                        new_node.board.is_empty(),
+                    );
 
                     new_node.hold = next_hold;
-                    Some(new_node)
+                    let step = Step {
+                        placement,
+                        hold: next_hold != self.hold,
+                        clear_type,
+                        attack: new_node.attack - self.attack,
+                    };
+                    Some((new_node, step))
                 } else {
                     None
                 }
@@ -166,45 +462,526 @@ impl Node {
     }
 }
 
-fn dfs(node: Node, queue: &mut VecDeque<Shape>) -> (usize, usize) {
-    if queue.is_empty() {
-        return (
-            node.attack,
-            node.attack + node.get_fall_height(Shape::I) + 1,
+///a placement along a line, boards are only rendered for the line that gets returned
+#[derive(Clone)]
+struct Step {
+    placement: BlPlacement,
+    hold: bool,
+    clear_type: ClearType,
+    attack: usize,
+}
+
+///best (atk, def) found below a node, the line to that attack, and whether it's exact.
+///pruned or cut short results aren't cached
+struct Outcome {
+    attack: usize,
+    defence: usize,
+    line: Vec<Step>,
+    exact: bool,
+}
+
+impl Outcome {
+    fn absorb(&mut self, other: Outcome) {
+        if other.attack > self.attack {
+            self.attack = other.attack;
+            self.line = other.line;
+        }
+        self.defence = self.defence.max(other.defence);
+        self.exact &= other.exact;
+    }
+}
+
+///(board, hold, queue index, btb, combo, garbage cancelled), everything a subtree's result depends on
+///besides the attack already sent on the way there. once the pending garbage is all cancelled
+///more attack only adds to the defence
+type TranspositionKey = (Board64, Shape, usize, usize, usize, usize);
+
+struct Search<'a> {
+    queue: &'a [Shape],
+    garbage: &'a [PendingGarbage],
+    pending_lines: usize,
+    table: HashMap<TranspositionKey, (usize, usize, Vec<Step>)>, //exact subtree results, see `to_table`
+    keep_lines: bool,                                            //lines stay empty unless asked for
+    step_bounds: Vec<usize>, //most attack one placement can send, by btb
+    best: (usize, usize),
+    budget: SolverBudget,
+    nodes: usize,
+    started: Instant,
+}
+
+impl<'a> Search<'a> {
+    fn new(
+        root: &Node,
+        queue: &'a [Shape],
+        garbage: &'a [PendingGarbage],
+        budget: SolverBudget,
+        keep_lines: bool,
+    ) -> Self {
+        //every clear type counted as a perfect clear, btb only grows by one per placement
+        //and the solver doesn't advance combo. kept non-decreasing so a lost btb can't beat the bound
+        let step_bounds = (0..=root.btb + queue.len())
+            .map(|btb| {
+                (0..CLEAR_TYPES as u8)
+                    .filter_map(|i| ClearType::try_from(i).ok())
+                    .map(|clear_type| {
+                        get_clear_attack(&root.ruleset, clear_type, root.combo, btb, true)
+                    })
+                    .max()
+                    .unwrap_or(0)
+                    + get_surge_attack(&root.ruleset, btb)
+            })
+            .scan(0, |most, step| {
+                *most = step.max(*most);
+                Some(*most)
+            })
+            .collect();
+        Self {
+            queue,
+            garbage,
+            pending_lines: garbage.iter().map(|chunk| chunk.amount).sum(),
+            table: HashMap::new(),
+            keep_lines,
+            step_bounds,
+            best: (0, 0),
+            budget,
+            nodes: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn out_of_budget(&self) -> bool {
+        self.budget
+            .max_nodes
+            .is_some_and(|max_nodes| self.nodes >= max_nodes)
+            || self
+                .budget
+                .max_time
+                .is_some_and(|max_time| self.started.elapsed() >= max_time)
+    }
+
+    ///upper bound on the attack a node can still add with the pieces it has left
+    fn attack_bound(&self, node: &Node, depth: usize) -> usize {
+        let pieces = self.queue.len() - depth;
+        self.step_bounds[node.btb..node.btb + pieces].iter().sum()
+    }
+
+    fn record(&mut self, outcome: &Outcome) {
+        self.best = (
+            self.best.0.max(outcome.attack),
+            self.best.1.max(outcome.defence),
         );
     }
-    let use_shape = queue.pop_front().unwrap();
 
-    let mut max_attack = 0;
-    let mut max_def = 0;
+    ///attack sent past what cancels the pending garbage
+    fn excess(&self, attack: usize) -> usize {
+        attack.saturating_sub(self.pending_lines)
+    }
+
+    ///lines of garbage a node could still take or cancel once its attack has cancelled what's pending
+    ///and the rest is tanked, measured by how far the next piece falls. none if tanking tops out
+    fn survival_margin(&self, node: &Node, shape: Shape) -> usize {
+        let cancelled = node.attack.min(self.pending_lines);
+        let fall = if cancelled == self.pending_lines {
+            node.get_fall_height(shape)
+        } else {
+            let mut board = node.board;
+            tank_garbage(&mut board, self.garbage, cancelled);
+            Node {
+                board,
+                ..node.clone()
+            }
+            .get_fall_height(shape)
+        };
+        if fall == 0 {
+            return 0;
+        }
+        self.excess(node.attack) + fall + 1
+    }
+
+    ///a line ending at this node
+    fn leaf(&mut self, node: &Node, shape: Shape, exact: bool) -> Outcome {
+        let outcome = Outcome {
+            attack: node.attack,
+            defence: self.survival_margin(node, shape),
+            line: Vec::new(),
+            exact,
+        };
+        self.record(&outcome);
+        outcome
+    }
+
+    fn dfs(&mut self, node: Node, depth: usize) -> Outcome {
+        if depth == self.queue.len() {
+            return self.leaf(&node, Shape::I, true);
+        }
+        let key = (
+            node.board,
+            node.hold,
+            depth,
+            node.btb,
+            node.combo,
+            node.attack.min(self.pending_lines),
+        );
+        if let Some((atk, def, line)) = self.table.get(&key) {
+            let outcome = Outcome {
+                attack: node.attack + atk,
+                defence: from_table(*def, self.excess(node.attack)),
+                line: line.clone(),
+                exact: true,
+            };
+            self.record(&outcome);
+            return outcome;
+        }
+
+        //pruning only when neither output can improve keeps both identical to the full search,
+        //cancelling and tanking only ever take away from the defence bound
+        let bound = node.attack + self.attack_bound(&node, depth);
+        let max_fall = node.ruleset.height + 2;
+        if bound <= self.best.0 && bound + max_fall + 1 <= self.best.1 {
+            return Outcome {
+                attack: node.attack,
+                defence: node.attack,
+                line: Vec::new(),
+                exact: false,
+            };
+        }
+
+        let use_shape = self.queue[depth];
+        let next = *self.queue.get(depth + 1).unwrap_or(&node.hold);
+        if self.out_of_budget() {
+            return self.leaf(&node, next, false);
+        }
+        self.nodes += 1;
+
+        let mut best = Outcome {
+            attack: 0,
+            defence: 0,
+            line: Vec::new(),
+            exact: true,
+        };
+
+        let mut branches = vec![(use_shape, node.hold)];
+        if use_shape != node.hold {
+            branches.push((node.hold, use_shape));
+        }
+        for (shape, next_hold) in branches {
+            let mut children = node.get_children(shape, next_hold);
+            if children.is_empty() {
+                let leaf = self.leaf(&node, next, true);
+                best.absorb(leaf);
+                continue;
+            }
+            //strongest lines first so the bound prunes as much as it can
+            children.sort_by(|(a, _), (b, _)| b.attack.cmp(&a.attack));
+            for (child, step) in children {
+                let mut outcome = self.dfs(child, depth + 1);
+                if self.keep_lines {
+                    outcome.line.insert(0, step);
+                }
+                best.absorb(outcome);
+            }
+        }
+
+        if best.exact {
+            self.table.insert(
+                key,
+                (
+                    best.attack - node.attack,
+                    to_table(best.defence, self.excess(node.attack)),
+                    best.line.clone(),
+                ),
+            );
+        }
+        best
+    }
+}
+
+///a subtree's defence as the table keeps it. nodes sharing a key only differ in attack once it's past
+///the pending garbage, and that excess adds straight onto every surviving leaf's margin. a topped out
+///subtree stays 0 whatever the excess, surviving ones are at least excess + 1 so never stored as 0
+fn to_table(defence: usize, excess: usize) -> usize {
+    if defence == 0 {
+        0
+    } else {
+        defence - excess
+    }
+}
 
-    let children: Vec<_> = node.get_children(use_shape, node.hold);
-    if children.is_empty() {
-        max_attack = max_attack.max(node.attack);
-        let height = node.get_fall_height(*queue.front().unwrap_or(&node.hold));
-        max_def = max_def.max(node.attack + height + 1);
+fn from_table(defence: usize, excess: usize) -> usize {
+    if defence == 0 {
+        0
     } else {
-        for child in children {
-            let (atk, def) = dfs(child, queue);
-            max_attack = max_attack.max(atk);
-            max_def = max_def.max(def)
+        defence + excess
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board_analyzer::board_from_rows;
+
+    fn tetris_ready() -> Board {
+        board_from_rows(&[
+            ".#########",
+            ".#########",
+            ".#########",
+            ".#########",
+            "#.########",
+        ])
+    }
+
+    fn solve_with_garbage(garbage: &[PendingGarbage]) -> (usize, usize) {
+        let queue = [MinoType::I, MinoType::I, MinoType::I];
+        solve_state_with_budget(
+            &tetris_ready(),
+            0,
+            0,
+            &queue,
+            garbage,
+            &Ruleset::default(),
+            SolverBudget::default(),
+        )
+    }
+
+    ///the search with no table, pruning or budget, what every shortcut has to agree with
+    fn exhaustive(search: &Search, node: Node, depth: usize) -> (usize, usize) {
+        if depth == search.queue.len() {
+            return (node.attack, search.survival_margin(&node, Shape::I));
+        }
+        let use_shape = search.queue[depth];
+        let next = *search.queue.get(depth + 1).unwrap_or(&node.hold);
+        let mut branches = vec![(use_shape, node.hold)];
+        if use_shape != node.hold {
+            branches.push((node.hold, use_shape));
         }
+        let mut best = (0, 0);
+        for (shape, next_hold) in branches {
+            let children = node.get_children(shape, next_hold);
+            if children.is_empty() {
+                best.0 = best.0.max(node.attack);
+                best.1 = best.1.max(search.survival_margin(&node, next));
+            }
+            for (child, _) in children {
+                let (attack, defence) = exhaustive(search, child, depth + 1);
+                best = (best.0.max(attack), best.1.max(defence));
+            }
+        }
+        best
     }
 
-    if use_shape != node.hold {
-        let children: Vec<_> = node.get_children(node.hold, use_shape);
-        if children.is_empty() {
-            max_attack = max_attack.max(node.attack);
-            let height = node.get_fall_height(*queue.front().unwrap_or(&node.hold));
-            max_def = max_def.max(node.attack + height + 1);
-        } else {
-            for child in children {
-                let (atk, def) = dfs(child, queue);
-                max_attack = max_attack.max(atk);
-                max_def = max_def.max(def)
+    ///(board, queue, pending garbage) covering quads, t-spins, hold and tanking
+    fn solver_fixtures() -> Vec<(Board, Vec<MinoType>, Vec<PendingGarbage>)> {
+        use MinoType::*;
+        let garbage = |amount| PendingGarbage {
+            amount,
+            column: Some(0),
+        };
+        let tsd = board_from_rows(&["...#......", "#...######", "##.#######"]);
+        let bumpy = board_from_rows(&["#.......##", "##..#..###", "###.##.###", "####.#####"]);
+        vec![
+            (tetris_ready(), vec![I, I, I], Vec::new()),
+            (tetris_ready(), vec![O, I, T, L], vec![garbage(3)]),
+            (tsd.clone(), vec![T, I, O, T], Vec::new()),
+            (tsd, vec![L, T, J, S], vec![garbage(2), garbage(1)]),
+            (bumpy.clone(), vec![I, T, O, L, J], Vec::new()),
+            (bumpy, vec![Z, S, I, T], vec![garbage(6)]),
+        ]
+    }
+
+    ///clear type and attack of `shape` landing on `cells`, and whether it could still move up from there
+    fn landing(
+        rows: &[&str],
+        shape: Shape,
+        spin_rules: SpinRules,
+        mut cells: [(i32, i32); 4],
+    ) -> (ClearType, usize, bool) {
+        let ruleset = Ruleset {
+            spin_rules,
+            ..Ruleset::default()
+        };
+        let hold = from_shape(shape);
+        let (node, _) = parse_replay_args(&board_from_rows(rows), 0, 0, &[hold], &ruleset);
+        cells.sort();
+        let (_, step) = node
+            .get_children(shape, shape)
+            .into_iter()
+            .find(|(_, step)| {
+                let mut placed = placement_cells(&step.placement);
+                placed.sort();
+                placed == cells
+            })
+            .expect("the piece reaches those cells");
+        let mobile = (step.placement + Offset { dx: 0, dy: 1 }).is_in_free_space(&node.board);
+        (step.clear_type, step.attack, mobile)
+    }
+
+    fn t_placement(rows: &[&str], cells: [(i32, i32); 4]) -> (ClearType, usize, bool) {
+        landing(rows, Shape::T, SpinRules::TSpins, cells)
+    }
+
+    #[test]
+    fn t_spins_follow_the_corner_rules() {
+        let tsd = ["...#......", "#...######", "##.#######"];
+        assert_eq!(
+            t_placement(&tsd, [(1, 1), (2, 1), (3, 1), (2, 0)]),
+            (ClearType::TspinDouble, 4, false)
+        );
+
+        let against_the_wall = [".#########"];
+        assert_eq!(
+            t_placement(&against_the_wall, [(0, 0), (0, 1), (0, 2), (1, 1)]),
+            (ClearType::TspinMiniSingle, 0, true)
+        ); //three corners with the walls, one of them in front and free to move up
+
+        let kicked_in = [
+            ".#........",
+            "..........",
+            "#.########",
+            "#..#######",
+            "#..#######",
+        ];
+        assert_eq!(
+            t_placement(&kicked_in, [(1, 0), (1, 1), (1, 2), (2, 1)]),
+            (ClearType::TspinDouble, 4, false)
+        ); //only the front corner above the point is filled, but the last srs kick makes it full
+    }
+
+    #[test]
+    fn all_mini_spins_by_lines_cleared() {
+        let cases = [
+            (
+                Shape::Z,
+                vec!["#..#.#..#.", "##.###..##", "#######..#", "##.#######"],
+                [(6, 2), (7, 1), (7, 2), (8, 1)],
+                ClearType::Single,
+                ClearType::AllspinSingle,
+                0,
+            ),
+            (
+                Shape::L,
+                vec![".#..#.....", "########.#", "######...#"],
+                [(6, 0), (7, 0), (8, 0), (8, 1)],
+                ClearType::Double,
+                ClearType::AllspinDouble,
+                1,
+            ),
+            (
+                Shape::S,
+                vec![
+                    "#.##...#..",
+                    "#####.####",
+                    "#####..###",
+                    "######.###",
+                    "######.###",
+                ],
+                [(5, 2), (5, 3), (6, 1), (6, 2)],
+                ClearType::Triple,
+                ClearType::AllspinTriple,
+                2,
+            ),
+        ];
+        for (shape, rows, cells, plain, spin, attack) in cases {
+            assert_eq!(
+                landing(&rows, shape, SpinRules::AllMini, cells),
+                (spin, attack, false),
+                "{shape}"
+            );
+            let (clear_type, _, _) = landing(&rows, shape, SpinRules::TSpins, cells);
+            assert_eq!(clear_type, plain, "{shape} without all-mini");
+        }
+    }
+
+    #[test]
+    fn no_spin_rules_score_t_spins_as_plain_clears() {
+        let tsd = ["...#......", "#...######", "##.#######"];
+        let cells = [(1, 1), (2, 1), (3, 1), (2, 0)];
+        assert_eq!(
+            landing(&tsd, Shape::T, SpinRules::None, cells),
+            (ClearType::Double, 1, false)
+        );
+    }
+
+    #[test]
+    fn budgeted_and_memoized_match_exhaustive() {
+        let ruleset = Ruleset::default();
+        let roomy = SolverBudget {
+            max_nodes: Some(1_000_000),
+            max_time: None,
+        };
+        for (i, (board, queue, garbage)) in solver_fixtures().into_iter().enumerate() {
+            let (root, shapes) = parse_replay_args(&board, 0, 0, &queue, &ruleset);
+            let shapes: Vec<_> = shapes.into();
+            let search = Search::new(&root, &shapes, &garbage, SolverBudget::default(), false);
+            let expected = exhaustive(&search, root, 0);
+
+            for budget in [SolverBudget::default(), roomy] {
+                let solved =
+                    solve_state_with_budget(&board, 0, 0, &queue, &garbage, &ruleset, budget);
+                assert_eq!(solved, expected, "fixture {i} {budget:?}");
             }
+            let line = solve_line(&board, 0, 0, &queue, &garbage, &ruleset);
+            assert_eq!((line.attack, line.defence), expected, "fixture {i} line");
+            let line_attack: usize = line.steps.iter().map(|step| step.attack).sum();
+            assert_eq!(line_attack, line.attack, "fixture {i} steps");
         }
     }
-    queue.push_front(use_shape);
-    (max_attack, max_def)
+
+    #[test]
+    fn solver_pool_is_only_sized_before_first_use() {
+        solver_pool();
+        assert!(matches!(
+            init_solver_pool(1),
+            Err(SolverPoolError::AlreadyBuilt)
+        ));
+    }
+
+    #[test]
+    fn exhausted_budget_never_overstates_attack() {
+        let ruleset = Ruleset::default();
+        let tight = SolverBudget {
+            max_nodes: Some(1),
+            max_time: None,
+        };
+        for (i, (board, queue, garbage)) in solver_fixtures().into_iter().enumerate() {
+            let full = solve_state_with_budget(
+                &board,
+                0,
+                0,
+                &queue,
+                &garbage,
+                &ruleset,
+                SolverBudget::default(),
+            );
+            let cut = solve_state_with_budget(&board, 0, 0, &queue, &garbage, &ruleset, tight);
+            assert!(cut.0 <= full.0, "fixture {i}");
+        }
+    }
+
+    #[test]
+    fn table_defence_round_trips() {
+        assert_eq!(from_table(to_table(0, 3), 7), 0);
+        assert_eq!(from_table(to_table(5, 3), 3), 5);
+        assert_eq!(from_table(to_table(5, 3), 6), 8);
+    }
+
+    #[test]
+    fn pending_garbage_beyond_the_line_attack() {
+        let (attack, defence) = solve_with_garbage(&[PendingGarbage {
+            amount: 10,
+            column: Some(0),
+        }]);
+        assert_eq!(attack, 4);
+        assert!(defence > 0);
+    }
+
+    #[test]
+    fn pending_garbage_topping_out() {
+        let chunk = PendingGarbage {
+            amount: 30,
+            column: Some(0),
+        };
+        let (attack, defence) = solve_with_garbage(&[chunk, chunk]);
+        assert_eq!(attack, 4);
+        assert_eq!(defence, 0);
+    }
 }
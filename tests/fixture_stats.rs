@@ -0,0 +1,92 @@
+//! stats built end to end from placements, no parser involved. the fixture is a 13 piece game of
+//! 44 keypresses over 312 frames whose last piece, a vertical I, clears a single
+
+use std::collections::HashMap;
+use std::fs;
+
+use action_parser::files::temp_path;
+use action_parser::placement_stats::CumulativePlacementStats;
+use action_parser::player_stats::PlayerStats;
+use action_parser::replay_response::{ClearType, PlacementStats};
+use action_parser::replay_source::{FixtureSource, PlacementArchive, PlayerGames, ReplaySource};
+use action_parser::ruleset::Ruleset;
+use serde_json::Value;
+
+const FIXTURE: &str = "tests/fixtures/placements/single_clear.json";
+
+fn fixture_source() -> FixtureSource {
+    let players: HashMap<String, Vec<Option<Vec<PlacementStats>>>> =
+        serde_json::from_str(&fs::read_to_string(FIXTURE).expect("fixture exists"))
+            .expect("fixture is placement json");
+    let players: PlayerGames = players.into_iter().collect();
+    FixtureSource::new().with_replay("single_clear", players)
+}
+
+#[test]
+fn fixture_placements_become_player_stats() {
+    let players = fixture_source()
+        .placements("single_clear", &["fixture".to_string()])
+        .unwrap();
+    let [(name, games)] = players.as_slice() else {
+        panic!("one player expected, got {}", players.len());
+    };
+    assert_eq!(name, "fixture");
+    let game = games[0].as_ref().expect("the game decoded");
+    assert_eq!(game.len(), 13);
+
+    let cumulative = CumulativePlacementStats::from(game.as_slice());
+    assert_eq!(cumulative.lines_cleared, 1);
+    assert_eq!(cumulative.attack, 0);
+
+    let stats = PlayerStats::from(&cumulative);
+    assert_eq!(stats.clear_types.get(&ClearType::Single), Some(&1));
+    assert_eq!(stats.pps, 2.5);
+    assert_eq!(stats.kpp, 44.0 / 13.0);
+    assert_eq!(stats.apm, 0.0);
+    assert_eq!(stats.garbage_received, 0);
+}
+
+#[test]
+fn fixture_source_filters_and_rejects_unknown_handles() {
+    let source = fixture_source();
+    assert!(source
+        .placements("single_clear", &["someone else".to_string()])
+        .unwrap()
+        .is_empty());
+    assert!(source.placements("missing", &[]).is_err());
+}
+
+#[test]
+fn fixture_is_in_the_current_placement_format() {
+    let raw: Value = serde_json::from_str(&fs::read_to_string(FIXTURE).unwrap()).unwrap();
+    let players: HashMap<String, Vec<Option<Vec<PlacementStats>>>> =
+        serde_json::from_value(raw.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&players).unwrap(), raw); //regenerate it when this fails
+    let game = players["fixture"][0].as_ref().unwrap();
+    assert!(game
+        .iter()
+        .all(|placement| placement.ruleset == Ruleset::default()));
+}
+
+#[test]
+fn archived_placements_recompute_the_same_stats() {
+    let dir = temp_path(&std::env::temp_dir(), "archive-test");
+    let archive = PlacementArchive::open(&dir).unwrap();
+    let players = fixture_source().placements("single_clear", &[]).unwrap();
+    archive.store("single_clear", &players).unwrap();
+    let handles = archive.handles();
+    let compressed = dir.join("single_clear.json.zst").is_file();
+    let archived = archive.placements("single_clear", &[]);
+    fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(handles.unwrap(), ["single_clear"]); //temp files written aside aren't listed
+    assert!(compressed);
+    let archived = archived.unwrap();
+    let stats = |players: &PlayerGames| {
+        let game = players[0].1[0].as_ref().unwrap();
+        serde_json::to_value(CumulativePlacementStats::from(game.as_slice())).unwrap()
+    };
+    assert_eq!(archived.len(), 1);
+    assert_eq!(archived[0].0, "fixture");
+    assert_eq!(stats(&archived), stats(&players));
+}
@@ -0,0 +1,95 @@
+//! native decoding checked against what the csdotnet parser recorded for the same replays,
+//! `<name>.ttrm` or `<name>.ttr` next to the `<name>.json` examples/record_parity.rs wrote for it
+
+use std::fs;
+use std::path::Path;
+
+use action_parser::replay_response::PlacementStats;
+use action_parser::replay_source::{
+    sanitize_string, NativeDecoder, PlacementDirectory, ReplaySource,
+};
+
+const FIXTURES: &str = "tests/fixtures/parity";
+
+///everything the parser reports for a placement that stats are built from
+fn assert_same_placement(context: &str, expected: &PlacementStats, decoded: &PlacementStats) {
+    assert_eq!(expected.shape, decoded.shape, "{context} shape");
+    assert_eq!(
+        expected.lines_cleared, decoded.lines_cleared,
+        "{context} lines cleared"
+    );
+    assert_eq!(
+        expected.garbage_cleared, decoded.garbage_cleared,
+        "{context} garbage cleared"
+    );
+    assert_eq!(
+        expected.clear_type, decoded.clear_type,
+        "{context} clear type"
+    );
+    assert_eq!(expected.combo, decoded.combo, "{context} combo");
+    assert_eq!(expected.btb_chain, decoded.btb_chain, "{context} btb chain");
+    assert_eq!(expected.btb_clear, decoded.btb_clear, "{context} btb clear");
+    assert_eq!(expected.attack, decoded.attack, "{context} attack");
+    assert_eq!(
+        expected.attack_received, decoded.attack_received,
+        "{context} attack received"
+    );
+    assert_eq!(
+        expected.attack_tanked, decoded.attack_tanked,
+        "{context} attack tanked"
+    );
+    assert_eq!(expected.board, decoded.board, "{context} board");
+}
+
+#[test]
+#[ignore = "needs replays recorded with examples/record_parity.rs in tests/fixtures/parity"]
+fn native_decoder_matches_recorded_parser_output() {
+    let recorded = PlacementDirectory::new(FIXTURES);
+    let mut checked = 0;
+
+    for entry in fs::read_dir(FIXTURES).expect("fixture directory exists") {
+        let path = entry.expect("readable fixture entry").path();
+        if !path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ext == "ttrm" || ext == "ttr")
+        {
+            continue;
+        }
+        let handle = path.file_stem().and_then(|stem| stem.to_str()).unwrap();
+        let expected = recorded
+            .placements(handle, &[])
+            .unwrap_or_else(|e| panic!("{handle} has no recorded parser output: {e}"));
+        let replay = fs::read_to_string(Path::new(&path)).unwrap();
+        let decoded = NativeDecoder
+            .placements(&sanitize_string(&replay), &[])
+            .unwrap_or_else(|e| panic!("{handle} doesn't decode: {e}"));
+
+        for (name, expected_games) in &expected {
+            let (_, decoded_games) = decoded
+                .iter()
+                .find(|(decoded_name, _)| decoded_name == name)
+                .unwrap_or_else(|| panic!("{handle} decoded without {name}"));
+            assert_eq!(
+                expected_games.len(),
+                decoded_games.len(),
+                "{handle} {name} games"
+            );
+            for (round, (expected, decoded)) in expected_games.iter().zip(decoded_games).enumerate()
+            {
+                let context = format!("{handle} {name} round {round}");
+                let (Some(expected), Some(decoded)) = (expected, decoded) else {
+                    assert_eq!(expected.is_some(), decoded.is_some(), "{context} corrupt");
+                    continue;
+                };
+                assert_eq!(expected.len(), decoded.len(), "{context} placements");
+                for (i, (expected, decoded)) in expected.iter().zip(decoded).enumerate() {
+                    assert_same_placement(&format!("{context} placement {i}"), expected, decoded);
+                }
+            }
+        }
+        checked += 1;
+    }
+    assert!(checked > 0, "no recorded replays in {FIXTURES}");
+    println!("{checked} replays match the recorded parser output");
+}